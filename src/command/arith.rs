@@ -0,0 +1,477 @@
+use thiserror::Error;
+
+use crate::variables::{is_valid_name, Variables};
+
+/// How deep variables whose values are themselves expressions may refer to each other
+const MAX_DEPTH: usize = 64;
+
+/// An expression that couldn't be evaluated
+#[derive(Debug, Error)]
+#[error("{expr}: {message}")]
+pub struct ArithError {
+    expr: String,
+    message: &'static str,
+}
+
+/// The message of an error, the expression it is about is added once it reaches `eval`
+type EvalResult<T> = Result<T, &'static str>;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Name(String),
+    Op(&'static str),
+}
+
+/// Operators, longer ones first so they win over their prefixes
+const OPERATORS: &[&str] = &[
+    "<<=", ">>=", "**", "++", "--", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "+=", "-=",
+    "*=", "/=", "%=", "&=", "^=", "|=", "+", "-", "*", "/", "%", "<", ">", "&", "|", "^", "!", "~",
+    "?", ":", "=", "(", ")", ",",
+];
+
+/// `=` and the operators that combine with it
+const ASSIGNMENTS: &[&str] = &[
+    "=", "*=", "/=", "%=", "+=", "-=", "<<=", ">>=", "&=", "^=", "|=",
+];
+
+/// Binary operators by precedence, loosest first. `**` and the assignments bind to the right
+/// and are handled on their own
+const BINARY: &[&[&str]] = &[
+    &["||"],
+    &["&&"],
+    &["|"],
+    &["^"],
+    &["&"],
+    &["==", "!="],
+    &["<", ">", "<=", ">="],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+/// Evaluates an arithmetic expression, as in `$(( ))`, `(( ))` and `let`. Variables are read
+/// and assigned in `vars`, a variable whose value isn't a number is evaluated as an
+/// expression of its own
+pub fn eval(expr: &str, vars: &mut Variables) -> Result<i64, ArithError> {
+    eval_nested(expr, vars, 0)
+}
+
+fn eval_nested(expr: &str, vars: &mut Variables, depth: usize) -> Result<i64, ArithError> {
+    let error = |message| ArithError {
+        expr: expr.trim().to_owned(),
+        message,
+    };
+    if depth > MAX_DEPTH {
+        return Err(error("expression recursion level exceeded"));
+    }
+
+    let tokens = tokenize(expr).map_err(error)?;
+    // an empty expression is 0
+    if tokens.is_empty() {
+        return Ok(0);
+    }
+    let mut evaluator = Evaluator {
+        tokens,
+        pos: 0,
+        vars,
+        depth,
+    };
+    let value = evaluator.comma(true).map_err(error)?;
+    if evaluator.pos < evaluator.tokens.len() {
+        return Err(error("syntax error in expression"));
+    }
+    Ok(value)
+}
+
+fn tokenize(expr: &str) -> EvalResult<Vec<Token>> {
+    let mut tokens = vec![];
+    let mut rest = expr;
+    loop {
+        rest = rest.trim_start();
+        let Some(c) = rest.chars().next() else {
+            return Ok(tokens);
+        };
+
+        if c.is_ascii_alphanumeric() || c == '_' {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '_' | '#' | '@')))
+                .unwrap_or(rest.len());
+            let word = &rest[..end];
+            rest = &rest[end..];
+            if c.is_ascii_digit() {
+                tokens.push(Token::Number(parse_number(word)?));
+            } else if is_valid_name(word) {
+                tokens.push(Token::Name(word.to_owned()));
+            } else {
+                return Err("syntax error in expression");
+            }
+            continue;
+        }
+
+        let op = OPERATORS
+            .iter()
+            .find(|op| rest.starts_with(**op))
+            .ok_or("syntax error: operand expected")?;
+        rest = &rest[op.len()..];
+        tokens.push(Token::Op(op));
+    }
+}
+
+/// Parses `N`, `0xN`, `0N` (octal) or `BASE#N`
+fn parse_number(word: &str) -> EvalResult<i64> {
+    let (base, digits) = if let Some((base, digits)) = word.split_once('#') {
+        match base.parse() {
+            Ok(base @ 2..=64) => (base, digits),
+            _ => return Err("invalid arithmetic base"),
+        }
+    } else if let Some(digits) = word.strip_prefix("0x").or(word.strip_prefix("0X")) {
+        (16, digits)
+    } else if word.len() > 1 && word.starts_with('0') {
+        (8, &word[1..])
+    } else {
+        (10, word)
+    };
+    if digits.is_empty() {
+        return Err("invalid number");
+    }
+
+    digits.chars().try_fold(0i64, |value, c| {
+        let digit = match c {
+            '0'..='9' => c as i64 - '0' as i64,
+            // letters are case-insensitive unless the base needs both cases
+            'a'..='z' => c as i64 - 'a' as i64 + 10,
+            'A'..='Z' if base <= 36 => c as i64 - 'A' as i64 + 10,
+            'A'..='Z' => c as i64 - 'A' as i64 + 36,
+            '@' => 62,
+            '_' => 63,
+            _ => return Err("invalid number"),
+        };
+        if digit >= base {
+            return Err("value too great for base");
+        }
+        Ok(value.wrapping_mul(base).wrapping_add(digit))
+    })
+}
+
+/// Evaluates while it parses. Every method takes `eval`, which is false on the side of a
+/// `&&`, `||` or `?:` that isn't taken: it is still parsed but assigns nothing and can't
+/// divide by zero
+struct Evaluator<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    vars: &'a mut Variables,
+    depth: usize,
+}
+
+impl Evaluator<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn peek_op(&self) -> Option<&'static str> {
+        match self.peek() {
+            Some(Token::Op(op)) => Some(op),
+            _ => None,
+        }
+    }
+
+    fn expect(&mut self, op: &str) -> EvalResult<()> {
+        if self.peek_op() != Some(op) {
+            return Err("syntax error in expression");
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn get(&mut self, name: &str) -> EvalResult<i64> {
+        let value = self.vars.get(name).unwrap_or_default();
+        if let Ok(n) = value.trim().parse() {
+            return Ok(n);
+        }
+        eval_nested(&value, self.vars, self.depth + 1).map_err(|e| e.message)
+    }
+
+    fn set(&mut self, name: &str, value: i64, eval: bool) {
+        if eval {
+            self.vars.set(name, value.to_string());
+        }
+    }
+
+    /// `a, b`, the value of the last one
+    fn comma(&mut self, eval: bool) -> EvalResult<i64> {
+        let mut value = self.assignment(eval)?;
+        while self.peek_op() == Some(",") {
+            self.pos += 1;
+            value = self.assignment(eval)?;
+        }
+        Ok(value)
+    }
+
+    fn assignment(&mut self, eval: bool) -> EvalResult<i64> {
+        let (Some(Token::Name(name)), Some(Token::Op(op))) =
+            (self.peek(), self.tokens.get(self.pos + 1))
+        else {
+            return self.conditional(eval);
+        };
+        if !ASSIGNMENTS.contains(op) {
+            return self.conditional(eval);
+        }
+        let (name, op) = (name.clone(), *op);
+        self.pos += 2;
+
+        let value = self.assignment(eval)?;
+        let value = match op.strip_suffix('=').unwrap() {
+            "" => value,
+            op => binary(op, self.get(&name)?, value, eval)?,
+        };
+        self.set(&name, value, eval);
+        Ok(value)
+    }
+
+    /// `cond ? a : b`
+    fn conditional(&mut self, eval: bool) -> EvalResult<i64> {
+        let cond = self.binary(0, eval)?;
+        if self.peek_op() != Some("?") {
+            return Ok(cond);
+        }
+        self.pos += 1;
+        let a = self.comma(eval && cond != 0)?;
+        self.expect(":")?;
+        let b = self.conditional(eval && cond == 0)?;
+        Ok(if cond != 0 { a } else { b })
+    }
+
+    /// The operators of `BINARY[level]` and everything that binds tighter
+    fn binary(&mut self, level: usize, eval: bool) -> EvalResult<i64> {
+        let Some(ops) = BINARY.get(level) else {
+            return self.power(eval);
+        };
+        let mut value = self.binary(level + 1, eval)?;
+        while let Some(op) = self.peek_op().filter(|op| ops.contains(op)) {
+            self.pos += 1;
+            // the right side of && and || only counts if the left doesn't decide it
+            let rhs_eval = match op {
+                "&&" => eval && value != 0,
+                "||" => eval && value == 0,
+                _ => eval,
+            };
+            let rhs = self.binary(level + 1, rhs_eval)?;
+            value = binary(op, value, rhs, rhs_eval)?;
+        }
+        Ok(value)
+    }
+
+    /// `a ** b`
+    fn power(&mut self, eval: bool) -> EvalResult<i64> {
+        let base = self.unary(eval)?;
+        if self.peek_op() != Some("**") {
+            return Ok(base);
+        }
+        self.pos += 1;
+        let exponent = self.power(eval)?;
+        binary("**", base, exponent, eval)
+    }
+
+    fn unary(&mut self, eval: bool) -> EvalResult<i64> {
+        let Some(op) = self.peek_op() else {
+            return self.postfix(eval);
+        };
+        match op {
+            "++" | "--" => {
+                if let Some(Token::Name(name)) = self.tokens.get(self.pos + 1) {
+                    let name = name.clone();
+                    self.pos += 2;
+                    let value = step(op, self.get(&name)?);
+                    self.set(&name, value, eval);
+                    return Ok(value);
+                }
+                // not a variable, so two signs that cancel out
+                self.pos += 1;
+                self.unary(eval)
+            }
+            "-" => {
+                self.pos += 1;
+                Ok(self.unary(eval)?.wrapping_neg())
+            }
+            "+" => {
+                self.pos += 1;
+                self.unary(eval)
+            }
+            "!" => {
+                self.pos += 1;
+                Ok((self.unary(eval)? == 0) as i64)
+            }
+            "~" => {
+                self.pos += 1;
+                Ok(!self.unary(eval)?)
+            }
+            _ => self.postfix(eval),
+        }
+    }
+
+    /// `x++`, `x--`, or a plain operand
+    fn postfix(&mut self, eval: bool) -> EvalResult<i64> {
+        match self.peek().cloned() {
+            Some(Token::Number(n)) => {
+                self.pos += 1;
+                Ok(n)
+            }
+            Some(Token::Name(name)) => {
+                self.pos += 1;
+                let value = self.get(&name)?;
+                if let Some(op @ ("++" | "--")) = self.peek_op() {
+                    self.pos += 1;
+                    self.set(&name, step(op, value), eval);
+                }
+                Ok(value)
+            }
+            Some(Token::Op("(")) => {
+                self.pos += 1;
+                let value = self.comma(eval)?;
+                self.expect(")")?;
+                Ok(value)
+            }
+            Some(Token::Op(_)) | None => Err("syntax error: operand expected"),
+        }
+    }
+}
+
+/// Applies `++` or `--`
+fn step(op: &str, value: i64) -> i64 {
+    match op {
+        "++" => value.wrapping_add(1),
+        _ => value.wrapping_sub(1),
+    }
+}
+
+/// Applies a binary operator. Unless `eval` is set, errors such as dividing by zero
+/// don't count
+fn binary(op: &str, a: i64, b: i64, eval: bool) -> EvalResult<i64> {
+    Ok(match op {
+        "||" => (a != 0 || b != 0) as i64,
+        "&&" => (a != 0 && b != 0) as i64,
+        "|" => a | b,
+        "^" => a ^ b,
+        "&" => a & b,
+        "==" => (a == b) as i64,
+        "!=" => (a != b) as i64,
+        "<" => (a < b) as i64,
+        ">" => (a > b) as i64,
+        "<=" => (a <= b) as i64,
+        ">=" => (a >= b) as i64,
+        "<<" => a.wrapping_shl(b as u32),
+        ">>" => a.wrapping_shr(b as u32),
+        "+" => a.wrapping_add(b),
+        "-" => a.wrapping_sub(b),
+        "*" => a.wrapping_mul(b),
+        "/" | "%" if b == 0 => {
+            if eval {
+                return Err("division by 0");
+            }
+            0
+        }
+        "/" => a.wrapping_div(b),
+        "%" => a.wrapping_rem(b),
+        "**" if b < 0 => {
+            if eval {
+                return Err("exponent less than 0");
+            }
+            0
+        }
+        "**" => a.wrapping_pow(b.try_into().unwrap_or(u32::MAX)),
+        _ => unreachable!("{op} is not a binary operator"),
+    })
+}
+
+#[test]
+fn test_precedence() {
+    let mut vars = Variables::from_env();
+    assert_eq!(eval("1 + 2 * 3", &mut vars).unwrap(), 7);
+    assert_eq!(eval("(1 + 2) * 3", &mut vars).unwrap(), 9);
+    assert_eq!(eval("1 + 2 == 3 && 4 < 5", &mut vars).unwrap(), 1);
+    assert_eq!(eval("1 | 2 ^ 3 & 4", &mut vars).unwrap(), 3);
+    assert_eq!(eval("-2 ** 2", &mut vars).unwrap(), 4);
+    assert_eq!(eval("1 << 2 + 1", &mut vars).unwrap(), 8);
+    assert_eq!(eval("7 % 4 * 2", &mut vars).unwrap(), 6);
+    assert_eq!(eval("!0 + ~0", &mut vars).unwrap(), 0);
+    assert_eq!(eval("1, 2, 3", &mut vars).unwrap(), 3);
+    assert_eq!(eval("", &mut vars).unwrap(), 0);
+}
+
+#[test]
+fn test_power() {
+    let mut vars = Variables::from_env();
+    // 2 ** (3 ** 2), not (2 ** 3) ** 2
+    assert_eq!(eval("2 ** 3 ** 2", &mut vars).unwrap(), 512);
+    assert_eq!(eval("2 ** 0", &mut vars).unwrap(), 1);
+    assert!(eval("2 ** -1", &mut vars).is_err());
+}
+
+#[test]
+fn test_conditional() {
+    let mut vars = Variables::from_env();
+    assert_eq!(eval("1 ? 2 : 3", &mut vars).unwrap(), 2);
+    assert_eq!(eval("0 ? 2 : 3", &mut vars).unwrap(), 3);
+    assert_eq!(eval("0 ? 1 : 0 ? 2 : 3", &mut vars).unwrap(), 3);
+    // only the side taken assigns, or divides
+    assert_eq!(eval("1 ? (t_a = 1) : (t_b = 1 / 0)", &mut vars).unwrap(), 1);
+    assert_eq!(vars.get("t_a").as_deref(), Some("1"));
+    assert_eq!(vars.get("t_b"), None);
+    assert!(eval("1 ? 2", &mut vars).is_err());
+}
+
+#[test]
+fn test_short_circuit() {
+    let mut vars = Variables::from_env();
+    assert_eq!(eval("0 && (t_c = 1)", &mut vars).unwrap(), 0);
+    assert_eq!(eval("1 || (t_c = 1)", &mut vars).unwrap(), 1);
+    assert_eq!(vars.get("t_c"), None);
+    assert_eq!(
+        eval("0 || 1 / 0", &mut vars).unwrap_err().to_string(),
+        "0 || 1 / 0: division by 0"
+    );
+    assert_eq!(eval("0 && 1 / 0", &mut vars).unwrap(), 0);
+    assert_eq!(eval("1 && (t_c = 2)", &mut vars).unwrap(), 1);
+    assert_eq!(vars.get("t_c").as_deref(), Some("2"));
+}
+
+#[test]
+fn test_assignment() {
+    let mut vars = Variables::from_env();
+    assert_eq!(eval("t_x = 5", &mut vars).unwrap(), 5);
+    assert_eq!(eval("t_x += 2", &mut vars).unwrap(), 7);
+    assert_eq!(eval("t_x *= 3", &mut vars).unwrap(), 21);
+    assert_eq!(eval("t_x <<= 1", &mut vars).unwrap(), 42);
+    assert_eq!(eval("t_x %= 5", &mut vars).unwrap(), 2);
+    assert_eq!(eval("t_y = t_x = 3", &mut vars).unwrap(), 3);
+    assert_eq!(vars.get("t_y").as_deref(), Some("3"));
+    assert_eq!(eval("t_x++ + ++t_x", &mut vars).unwrap(), 8);
+    assert_eq!(vars.get("t_x").as_deref(), Some("5"));
+    // a value that isn't a number is an expression of its own
+    vars.set("t_z", "t_x * 2".into());
+    assert_eq!(eval("t_z + 1", &mut vars).unwrap(), 11);
+    assert!(eval("1 = 2", &mut vars).is_err());
+}
+
+#[test]
+fn test_numbers() {
+    let mut vars = Variables::from_env();
+    assert_eq!(eval("010", &mut vars).unwrap(), 8);
+    assert_eq!(eval("0x1f", &mut vars).unwrap(), 31);
+    assert_eq!(eval("0XFF", &mut vars).unwrap(), 255);
+    assert_eq!(eval("2#101", &mut vars).unwrap(), 5);
+    assert_eq!(eval("16#ff", &mut vars).unwrap(), 255);
+    assert_eq!(eval("36#Z", &mut vars).unwrap(), 35);
+    assert_eq!(eval("64#A", &mut vars).unwrap(), 36);
+    assert_eq!(eval("64#_", &mut vars).unwrap(), 63);
+    assert_eq!(
+        eval("08", &mut vars).unwrap_err().to_string(),
+        "08: value too great for base"
+    );
+    assert_eq!(
+        eval("1#0", &mut vars).unwrap_err().to_string(),
+        "1#0: invalid arithmetic base"
+    );
+    assert!(eval("0x", &mut vars).is_err());
+}
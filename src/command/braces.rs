@@ -0,0 +1,248 @@
+use super::parser::{Word, WordPart};
+
+/// A word broken up for brace expansion: every unquoted character on its own, and
+/// everything else whole since it can't take part
+#[derive(Clone, Copy)]
+enum Atom<'a> {
+    Char(char),
+    Part(&'a WordPart),
+}
+
+/// Expands `{a,b,c}` lists and `{x..y[..step]}` sequences, giving the words in order.
+/// Only unquoted braces count, and braces that are neither stay as they are
+pub fn expand(word: &Word) -> Vec<Word> {
+    // the common case, nothing to do
+    let has_brace = word.0.iter().any(|part| match part {
+        WordPart::Literal(s) => s.contains('{'),
+        _ => false,
+    });
+    if !has_brace {
+        return vec![word.clone()];
+    }
+
+    let atoms: Vec<_> = word
+        .0
+        .iter()
+        .flat_map(|part| match part {
+            WordPart::Literal(s) => s.chars().map(Atom::Char).collect(),
+            part => vec![Atom::Part(part)],
+        })
+        .collect();
+    expand_atoms(&atoms)
+        .iter()
+        .map(|atoms| to_word(atoms))
+        .collect()
+}
+
+fn expand_atoms<'a>(atoms: &[Atom<'a>]) -> Vec<Vec<Atom<'a>>> {
+    // the first `{` that opens a list or a sequence, anything before it is left alone
+    for open in 0..atoms.len() {
+        if !matches!(atoms[open], Atom::Char('{')) {
+            continue;
+        }
+        let Some((close, commas)) = find_close(atoms, open) else {
+            continue;
+        };
+
+        let inside = &atoms[open + 1..close];
+        let alternatives: Vec<Vec<Atom>> = if !commas.is_empty() {
+            let mut bounds = vec![open];
+            bounds.extend(commas);
+            bounds.push(close);
+            bounds
+                .windows(2)
+                .flat_map(|w| expand_atoms(&atoms[w[0] + 1..w[1]]))
+                .collect()
+        } else if let Some(sequence) = sequence(inside) {
+            sequence
+                .into_iter()
+                .map(|s| s.chars().map(Atom::Char).collect())
+                .collect()
+        } else {
+            continue;
+        };
+
+        let prefix = &atoms[..open];
+        let suffixes = expand_atoms(&atoms[close + 1..]);
+        let mut words = vec![];
+        for alternative in &alternatives {
+            for suffix in &suffixes {
+                words.push([prefix, alternative, suffix].concat());
+            }
+        }
+        return words;
+    }
+    vec![atoms.to_vec()]
+}
+
+/// The index of the `}` matching the `{` at `open`, and of the commas between them that
+/// aren't inside nested braces
+fn find_close(atoms: &[Atom], open: usize) -> Option<(usize, Vec<usize>)> {
+    let mut depth = 0;
+    let mut commas = vec![];
+    for (i, atom) in atoms.iter().enumerate().skip(open + 1) {
+        match atom {
+            Atom::Char('{') => depth += 1,
+            Atom::Char('}') if depth == 0 => return Some((i, commas)),
+            Atom::Char('}') => depth -= 1,
+            Atom::Char(',') if depth == 0 => commas.push(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// The values of `x..y` or `x..y..step`, where `x` and `y` are both integers or both
+/// single letters. Integers written with leading zeros are padded to the same width
+fn sequence(atoms: &[Atom]) -> Option<Vec<String>> {
+    let text = atoms
+        .iter()
+        .map(|atom| match atom {
+            Atom::Char(c) => Some(*c),
+            Atom::Part(_) => None,
+        })
+        .collect::<Option<String>>()?;
+
+    let mut bounds = text.split("..");
+    let (start, end) = (bounds.next()?, bounds.next()?);
+    let step = match bounds.next() {
+        Some(step) => step.parse::<i64>().ok()?.unsigned_abs().max(1),
+        None => 1,
+    };
+    if bounds.next().is_some() {
+        return None;
+    }
+
+    if let (Ok(from), Ok(to)) = (start.parse::<i64>(), end.parse::<i64>()) {
+        let padded = |s: &str| {
+            let digits = s.trim_start_matches('-');
+            digits.len() > 1 && digits.starts_with('0')
+        };
+        let width = if padded(start) || padded(end) {
+            start.len().max(end.len())
+        } else {
+            0
+        };
+        return Some(
+            range(from, to, step)
+                .map(|n| format!("{n:0width$}"))
+                .collect(),
+        );
+    }
+
+    let letter = |s: &str| {
+        let mut chars = s.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) if c.is_ascii_alphabetic() => Some(c),
+            _ => None,
+        }
+    };
+    let (from, to) = (letter(start)?, letter(end)?);
+    Some(
+        range(from as i64, to as i64, step)
+            .map(|c| char::from(c as u8).to_string())
+            .collect(),
+    )
+}
+
+/// `from` to `to` inclusive, counting down if `to` is smaller
+fn range(from: i64, to: i64, step: u64) -> impl Iterator<Item = i64> {
+    let count = from.abs_diff(to) / step;
+    (0..=count).map(move |i| {
+        if from <= to {
+            from.wrapping_add_unsigned(i * step)
+        } else {
+            from.wrapping_sub_unsigned(i * step)
+        }
+    })
+}
+
+/// Puts the word back together, joining up characters into literals
+fn to_word(atoms: &[Atom]) -> Word {
+    let mut word = Word::default();
+    for atom in atoms {
+        match atom {
+            Atom::Char(c) => word.push_literal(*c),
+            Atom::Part(part) => word.0.push((*part).clone()),
+        }
+    }
+    word
+}
+
+/// Expands an unquoted word, giving back the text of each one
+#[cfg(test)]
+fn expand_str(s: &str) -> Vec<String> {
+    expand(&Word(vec![WordPart::Literal(s.into())]))
+        .into_iter()
+        .map(|word| match &word.0[..] {
+            [WordPart::Literal(s)] => s.clone(),
+            parts => panic!("expected a single literal, got {parts:?}"),
+        })
+        .collect()
+}
+
+#[test]
+fn test_lists() {
+    assert_eq!(expand_str("a{b,c}d"), ["abd", "acd"]);
+    assert_eq!(expand_str("{a,b}{1,2}"), ["a1", "a2", "b1", "b2"]);
+    assert_eq!(expand_str("{a,b{1,2},c}"), ["a", "b1", "b2", "c"]);
+    assert_eq!(expand_str("x{,.bak}"), ["x", "x.bak"]);
+    // not a list or a sequence, left alone
+    assert_eq!(expand_str("{}"), ["{}"]);
+    assert_eq!(expand_str("{a}"), ["{a}"]);
+    assert_eq!(expand_str("{a,b"), ["{a,b"]);
+    assert_eq!(expand_str("{a}{b,c}"), ["{a}b", "{a}c"]);
+}
+
+#[test]
+fn test_sequences() {
+    assert_eq!(expand_str("{1..3}"), ["1", "2", "3"]);
+    assert_eq!(expand_str("{3..1}"), ["3", "2", "1"]);
+    assert_eq!(expand_str("{-2..2..2}"), ["-2", "0", "2"]);
+    assert_eq!(expand_str("{1..10..-3}"), ["1", "4", "7", "10"]);
+    assert_eq!(expand_str("{01..10..3}"), ["01", "04", "07", "10"]);
+    assert_eq!(expand_str("{8..010}"), ["008", "009", "010"]);
+    assert_eq!(expand_str("{a..e..2}"), ["a", "c", "e"]);
+    assert_eq!(expand_str("{z..x}"), ["z", "y", "x"]);
+    assert_eq!(expand_str("f{1..2}.txt"), ["f1.txt", "f2.txt"]);
+    // mixed or malformed bounds stay as they are
+    assert_eq!(expand_str("{1..b}"), ["{1..b}"]);
+    assert_eq!(expand_str("{1..2..3..4}"), ["{1..2..3..4}"]);
+    assert_eq!(expand_str("{ab..c}"), ["{ab..c}"]);
+}
+
+#[test]
+fn test_quoted() {
+    // a quoted brace or comma doesn't count
+    let word = Word(vec![
+        WordPart::Literal("a".into()),
+        WordPart::Quoted("{b,c}".into()),
+    ]);
+    assert_eq!(expand(&word), std::slice::from_ref(&word));
+
+    let word = Word(vec![
+        WordPart::Literal("{a".into()),
+        WordPart::Quoted(",".into()),
+        WordPart::Literal("b}".into()),
+    ]);
+    assert_eq!(expand(&word), std::slice::from_ref(&word));
+
+    // the quoted part is carried along into every word
+    let word = Word(vec![
+        WordPart::Quoted("x".into()),
+        WordPart::Literal("{1,2}".into()),
+    ]);
+    assert_eq!(
+        expand(&word),
+        [
+            Word(vec![
+                WordPart::Quoted("x".into()),
+                WordPart::Literal("1".into())
+            ]),
+            Word(vec![
+                WordPart::Quoted("x".into()),
+                WordPart::Literal("2".into())
+            ]),
+        ]
+    );
+}
@@ -0,0 +1,274 @@
+use thiserror::Error;
+
+use super::{
+    arith::{self, ArithError},
+    braces, capture_output, glob,
+    parser::{Word, WordPart},
+    redirect::RedirectError,
+};
+use crate::shell::Shell;
+
+const DEFAULT_IFS: &str = " \t\n";
+
+/// Why a command's words or redirections couldn't be expanded, the command isn't run
+#[derive(Debug, Error)]
+pub enum ExpandError {
+    #[error(transparent)]
+    Arith(#[from] ArithError),
+    #[error(transparent)]
+    Redirect(#[from] RedirectError),
+    /// a glob pattern matched nothing and `failglob` is set
+    #[error("no match: {0}")]
+    NoMatch(String),
+}
+
+/// Expands a word into zero or more fields. Unquoted parameter expansions and command
+/// substitutions are split on `$IFS`, everything else is kept together. Fields with
+/// unquoted glob characters are then replaced by the paths they match.
+///
+/// The variables are only locked while a parameter is looked up, the commands of a
+/// substitution need them too
+pub fn expand_word(word: &Word, shell: &'static Shell) -> Result<Vec<String>, ExpandError> {
+    let vars = || shell.variables.lock().unwrap();
+    let ifs = vars().get("IFS").unwrap_or_else(|| DEFAULT_IFS.into());
+    let mut fields = Fields::default();
+
+    for part in &word.0 {
+        match part {
+            WordPart::Literal(s) => fields.push(s, false),
+            WordPart::Quoted(s) => fields.push(s, true),
+            // each positional parameter is a field of its own, even when quoted
+            WordPart::Param { name, quoted: true } if name == "@" => {
+                for (i, param) in vars().positional.iter().enumerate() {
+                    if i > 0 {
+                        fields.end();
+                    }
+                    fields.push(param, true);
+                }
+            }
+            WordPart::Param { name, quoted: true } => {
+                fields.push(&vars().get(name).unwrap_or_default(), true);
+            }
+            WordPart::Param {
+                name,
+                quoted: false,
+            } if name == "@" || name == "*" => {
+                for (i, param) in vars().positional.iter().enumerate() {
+                    if i > 0 && fields.started {
+                        fields.end();
+                    }
+                    fields.split(param, &ifs);
+                }
+            }
+            WordPart::Param {
+                name,
+                quoted: false,
+            } => {
+                let value = vars().get(name).unwrap_or_default();
+                fields.split(&value, &ifs);
+            }
+            WordPart::Command {
+                source,
+                quoted: true,
+            } => fields.push(&capture_output(source, shell), true),
+            WordPart::Command {
+                source,
+                quoted: false,
+            } => {
+                let output = capture_output(source, shell);
+                fields.split(&output, &ifs);
+            }
+            WordPart::Arith { expr, quoted: true } => {
+                fields.push(&expand_arith(expr, shell)?, true);
+            }
+            WordPart::Arith {
+                expr,
+                quoted: false,
+            } => {
+                let value = expand_arith(expr, shell)?;
+                fields.split(&value, &ifs);
+            }
+        }
+    }
+
+    let options = vars().options.clone();
+    let mut expanded = vec![];
+    for (field, pattern) in fields.finish() {
+        if !glob::is_pattern(&pattern) {
+            expanded.push(field);
+            continue;
+        }
+        let paths = glob::expand(&pattern, &options);
+        if !paths.is_empty() {
+            expanded.extend(paths);
+        } else if options.failglob {
+            return Err(ExpandError::NoMatch(field));
+        } else if !options.nullglob {
+            // a pattern that matches nothing is left as it is
+            expanded.push(field);
+        }
+    }
+    Ok(expanded)
+}
+
+/// The fields a word expands to, each kept along with a glob pattern version of itself
+/// in which the characters that were quoted are escaped
+#[derive(Default)]
+struct Fields {
+    done: Vec<(String, String)>,
+    cur: String,
+    pattern: String,
+    /// distinguishes "" (one empty field) from an empty unquoted expansion (no field)
+    started: bool,
+}
+
+impl Fields {
+    /// Adds to the field being built
+    fn push(&mut self, s: &str, quoted: bool) {
+        self.cur.push_str(s);
+        if quoted {
+            self.pattern.push_str(&glob::escape(s));
+        } else {
+            self.pattern.push_str(s);
+        }
+        self.started = true;
+    }
+
+    fn end(&mut self) {
+        let field = (
+            std::mem::take(&mut self.cur),
+            std::mem::take(&mut self.pattern),
+        );
+        self.done.push(field);
+        self.started = false;
+    }
+
+    /// Splits `value` on `ifs`, the first piece joins the field being built and the last
+    /// one is left for whatever follows it in the word
+    fn split(&mut self, value: &str, ifs: &str) {
+        let mut pieces = value.split(|c| ifs.contains(c)).peekable();
+        while let Some(piece) = pieces.next() {
+            if !piece.is_empty() {
+                self.push(piece, false);
+            }
+            if pieces.peek().is_some() && self.started {
+                self.end();
+            }
+        }
+    }
+
+    fn finish(mut self) -> Vec<(String, String)> {
+        if self.started {
+            self.end();
+        }
+        self.done
+    }
+}
+
+/// Evaluates the expression of `$(( ))` once its own expansions are done
+fn expand_arith(expr: &Word, shell: &'static Shell) -> Result<String, ExpandError> {
+    let expr = expand_quoted(expr, shell)?;
+    let value = arith::eval(&expr, &mut shell.variables.lock().unwrap())?;
+    Ok(value.to_string())
+}
+
+/// Expands the words of a command, brace expansion coming before everything else
+pub fn expand_words(words: &[Word], shell: &'static Shell) -> Result<Vec<String>, ExpandError> {
+    let mut fields = vec![];
+    for word in words.iter().flat_map(braces::expand) {
+        fields.extend(expand_word(&word, shell)?);
+    }
+    Ok(fields)
+}
+
+/// Expands a word as if it were inside double quotes, i.e. without field splitting
+pub fn expand_quoted(word: &Word, shell: &'static Shell) -> Result<String, ExpandError> {
+    word.0
+        .iter()
+        .map(|part| match part {
+            WordPart::Literal(s) | WordPart::Quoted(s) => Ok(s.clone()),
+            WordPart::Param { name, .. } => Ok(shell
+                .variables
+                .lock()
+                .unwrap()
+                .get(name)
+                .unwrap_or_default()),
+            WordPart::Command { source, .. } => Ok(capture_output(source, shell)),
+            WordPart::Arith { expr, .. } => expand_arith(expr, shell),
+        })
+        .collect()
+}
+
+/// Expands a redirection target, which must end up as exactly one field
+pub fn expand_to_string(word: &Word, shell: &'static Shell) -> Result<String, ExpandError> {
+    let mut fields = expand_word(word, shell)?;
+    match fields.len() {
+        1 => Ok(fields.remove(0)),
+        _ => Err(RedirectError::ambiguous(word).into()),
+    }
+}
+
+#[test]
+fn test_no_match() {
+    let shell: &'static Shell = Box::leak(Box::new(Shell::new(
+        crate::history::History::default(),
+        false,
+    )));
+    let word = Word(vec![WordPart::Literal("/no/such/dir/*.txt".into())]);
+    let set = |name, on| {
+        *shell
+            .variables
+            .lock()
+            .unwrap()
+            .options
+            .by_name(name)
+            .unwrap() = on
+    };
+
+    // a pattern that matches nothing is left as it is
+    assert_eq!(expand_word(&word, shell).unwrap(), ["/no/such/dir/*.txt"]);
+
+    set("nullglob", true);
+    assert!(expand_word(&word, shell).unwrap().is_empty());
+
+    // failglob wins over nullglob
+    set("failglob", true);
+    assert!(matches!(
+        expand_word(&word, shell),
+        Err(ExpandError::NoMatch(field)) if field == "/no/such/dir/*.txt"
+    ));
+}
+
+#[test]
+fn test_redirect_target() {
+    let shell: &'static Shell = Box::leak(Box::new(Shell::new(
+        crate::history::History::default(),
+        false,
+    )));
+    shell
+        .variables
+        .lock()
+        .unwrap()
+        .set("t_target", "a b".into());
+    let param = |quoted| {
+        Word(vec![WordPart::Param {
+            name: "t_target".into(),
+            quoted,
+        }])
+    };
+
+    assert_eq!(expand_to_string(&param(true), shell).unwrap(), "a b");
+    // splitting into several fields, or none, leaves no one file to open
+    assert!(matches!(
+        expand_to_string(&param(false), shell),
+        Err(ExpandError::Redirect(_))
+    ));
+    let empty = Word(vec![WordPart::Param {
+        name: "t_unset".into(),
+        quoted: false,
+    }]);
+    assert_eq!(
+        expand_to_string(&empty, shell).unwrap_err().to_string(),
+        "$t_unset: ambiguous redirect"
+    );
+}
@@ -0,0 +1,309 @@
+use std::fs;
+
+use crate::variables::Options;
+
+/// true if `pattern` has an unescaped `*`, `?` or `[...]`. A `[` that is never closed,
+/// like the one of `[ -f file ]`, is just a character
+pub fn is_pattern(pattern: &str) -> bool {
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '*' | '?' => return true,
+            '[' if chars.clone().any(|c| c == ']') => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+/// Escapes the characters that are special in a pattern, so that `text` only matches itself
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// The paths matching `pattern`, sorted. Names starting with `.` are only matched by a
+/// pattern that starts with a `.` of its own, unless `dotglob` is set
+pub fn expand(pattern: &str, options: &Options) -> Vec<String> {
+    let components: Vec<_> = pattern.split('/').collect();
+    let mut paths = vec![];
+    match components.split_first() {
+        // an absolute path, the first component is the empty string before the `/`
+        Some((&"", rest)) if !rest.is_empty() => expand_in("/".into(), rest, options, &mut paths),
+        _ => expand_in(String::new(), &components, options, &mut paths),
+    }
+    paths.sort();
+    paths.dedup();
+    paths
+}
+
+/// Matches `components` against what is under `dir`, which is empty for the working
+/// directory or ends with a `/`
+fn expand_in(dir: String, components: &[&str], options: &Options, paths: &mut Vec<String>) {
+    let Some((&component, rest)) = components.split_first() else {
+        return;
+    };
+
+    match component {
+        // `a//b`, or the trailing slash of `*/` which only lets directories through
+        "" => {
+            if rest.is_empty() {
+                if !dir.is_empty() {
+                    paths.push(dir);
+                }
+            } else {
+                expand_in(dir, rest, options, paths);
+            }
+        }
+        "**" if options.globstar => {
+            // `**` on its own matches everything below `dir`, otherwise it stands for any
+            // number of directories, including none
+            if !rest.is_empty() {
+                expand_in(dir.clone(), rest, options, paths);
+            }
+            for entry in entries(&dir, "*", options) {
+                let path = format!("{dir}{}", entry.name);
+                if rest.is_empty() {
+                    paths.push(path.clone());
+                }
+                // like bash, links to directories aren't followed, they could loop forever
+                if entry.is_dir && !entry.is_link {
+                    expand_in(format!("{path}/"), components, options, paths);
+                }
+            }
+        }
+        _ if !is_pattern(component) => {
+            let path = format!("{dir}{}", unescape(component));
+            if rest.is_empty() {
+                if fs::symlink_metadata(&path).is_ok() {
+                    paths.push(path);
+                }
+            } else {
+                expand_in(format!("{path}/"), rest, options, paths);
+            }
+        }
+        _ => {
+            for entry in entries(&dir, component, options) {
+                let path = format!("{dir}{}", entry.name);
+                if rest.is_empty() {
+                    paths.push(path);
+                } else if entry.is_dir {
+                    expand_in(format!("{path}/"), rest, options, paths);
+                }
+            }
+        }
+    }
+}
+
+/// A name found in a directory
+struct Entry {
+    name: String,
+    /// a directory, or a link to one
+    is_dir: bool,
+    is_link: bool,
+}
+
+/// The names in `dir` matching `pattern`
+fn entries(dir: &str, pattern: &str, options: &Options) -> Vec<Entry> {
+    let Ok(read_dir) = fs::read_dir(if dir.is_empty() { "." } else { dir }) else {
+        return vec![];
+    };
+    let pattern: Vec<_> = pattern.chars().collect();
+    let show_hidden = options.dotglob || pattern.first() == Some(&'.');
+
+    read_dir
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            if name.starts_with('.') && !show_hidden {
+                return None;
+            }
+            let chars: Vec<_> = name.chars().collect();
+            if !matches(&pattern, &chars) {
+                return None;
+            }
+            let is_dir = fs::metadata(entry.path()).is_ok_and(|m| m.is_dir());
+            let is_link = entry.file_type().is_ok_and(|t| t.is_symlink());
+            Some(Entry {
+                name,
+                is_dir,
+                is_link,
+            })
+        })
+        .collect()
+}
+
+/// true if the whole of `name` matches `pattern`
+fn matches(pattern: &[char], name: &[char]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some(('*', rest)) => (0..=name.len()).any(|i| matches(rest, &name[i..])),
+        Some(('?', rest)) => !name.is_empty() && matches(rest, &name[1..]),
+        Some(('[', rest)) => match bracket(rest, name.first().copied()) {
+            Some((matched, after)) => matched && matches(after, &name[1..]),
+            // no closing `]`, so the `[` is just a character
+            None => name.first() == Some(&'[') && matches(rest, &name[1..]),
+        },
+        Some(('\\', [c, rest @ ..])) => name.first() == Some(c) && matches(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && matches(rest, &name[1..]),
+    }
+}
+
+/// Matches `c` against the `[...]` that `pattern` starts just after, returning whether it
+/// matched and the rest of the pattern. None if the bracket is never closed
+fn bracket(pattern: &[char], c: Option<char>) -> Option<(bool, &[char])> {
+    let (negate, mut pattern) = match pattern.split_first() {
+        Some(('!' | '^', rest)) => (true, rest),
+        _ => (false, pattern),
+    };
+
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        let (start, rest) = match pattern {
+            // a `]` straight after the `[` is part of the set
+            [']', rest @ ..] if !first => return Some((c.is_some() && matched != negate, rest)),
+            ['\\', start, rest @ ..] | [start, rest @ ..] => (*start, rest),
+            [] => return None,
+        };
+        first = false;
+        pattern = rest;
+
+        let end = match pattern {
+            ['-', end, rest @ ..] if *end != ']' => {
+                pattern = rest;
+                *end
+            }
+            _ => start,
+        };
+        matched |= c.is_some_and(|c| start <= c && c <= end);
+    }
+}
+
+/// Removes the backslashes `escape` added
+fn unescape(pattern: &str) -> String {
+    let mut unescaped = String::with_capacity(pattern.len());
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unescaped.extend(chars.next()),
+            _ => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+#[test]
+fn test_matches() {
+    let glob = |pattern: &str, name: &str| {
+        let pattern: Vec<_> = pattern.chars().collect();
+        let name: Vec<_> = name.chars().collect();
+        matches(&pattern, &name)
+    };
+    assert!(glob("*.rs", "main.rs"));
+    assert!(glob("*", ""));
+    assert!(!glob("*.rs", "main.rsx"));
+    assert!(glob("a*b*c", "aXbYbc"));
+    assert!(glob("?", "x"));
+    assert!(!glob("?", ""));
+    assert!(!glob("??", "x"));
+    assert!(glob("\\*", "*"));
+    assert!(!glob("\\*", "x"));
+
+    assert!(glob("[abc]", "b"));
+    assert!(!glob("[abc]", "d"));
+    assert!(glob("[a-c]x", "bx"));
+    assert!(!glob("[a-c]", "d"));
+    assert!(glob("[!a-c]", "d"));
+    assert!(glob("[^a-c]", "d"));
+    assert!(!glob("[!a-c]", "a"));
+    // a `]` straight after the `[` or `[!` is part of the set
+    assert!(glob("[]]", "]"));
+    assert!(glob("[!]]", "a"));
+    assert!(!glob("[!]]", "]"));
+    // a `-` at either end is just a character
+    assert!(glob("[a-]", "-"));
+    assert!(glob("[\\]]", "]"));
+    // never closed, so it is just a `[`
+    assert!(glob("[ab", "[ab"));
+    assert!(!glob("[ab", "a"));
+}
+
+#[test]
+fn test_bracket() {
+    let chars: Vec<_> = "a-c]rest".chars().collect();
+    let (matched, rest) = bracket(&chars, Some('b')).unwrap();
+    assert!(matched);
+    assert_eq!(rest.iter().collect::<String>(), "rest");
+    // nothing left in the name never matches, even negated
+    assert!(!bracket(&['!', 'a', ']'], None).unwrap().0);
+    assert!(bracket(&['a', 'b'], Some('a')).is_none());
+}
+
+#[test]
+fn test_is_pattern() {
+    assert!(is_pattern("*.rs"));
+    assert!(is_pattern("a?"));
+    assert!(is_pattern("[ab]"));
+    assert!(!is_pattern("plain"));
+    assert!(!is_pattern("\\*"));
+    assert!(!is_pattern("[ -f file"));
+}
+
+#[test]
+fn test_escape() {
+    assert_eq!(escape("a*b?[c]\\"), "a\\*b\\?\\[c\\]\\\\");
+    assert_eq!(unescape(&escape("a*b?[c]\\")), "a*b?[c]\\");
+    assert!(!is_pattern(&escape("*.rs")));
+    let pattern: Vec<_> = escape("[a]*").chars().collect();
+    let name: Vec<_> = "[a]*".chars().collect();
+    assert!(matches(&pattern, &name));
+    assert!(!matches(&pattern, &['a']));
+}
+
+#[test]
+fn test_expand() {
+    let dir = std::env::temp_dir().join(format!("shell-glob-{}", std::process::id()));
+    fs::create_dir_all(dir.join("sub/deep")).unwrap();
+    for file in ["a.rs", "b.rs", ".hidden.rs", "sub/c.rs", "sub/deep/d.rs"] {
+        fs::write(dir.join(file), "").unwrap();
+    }
+    let dir = dir.to_str().unwrap();
+    let glob = |pattern: &str, options: &Options| -> Vec<_> {
+        expand(&format!("{dir}/{pattern}"), options)
+            .into_iter()
+            .map(|path| path[dir.len() + 1..].to_owned())
+            .collect()
+    };
+
+    let mut options = Options::default();
+    assert_eq!(glob("*.rs", &options), ["a.rs", "b.rs"]);
+    assert_eq!(glob("*/", &options), ["sub/"]);
+    assert_eq!(glob("*/*.rs", &options), ["sub/c.rs"]);
+    // names starting with `.` need a `.` of their own
+    assert_eq!(glob(".*.rs", &options), [".hidden.rs"]);
+    assert!(glob("*.txt", &options).is_empty());
+    // without globstar `**` is just `*`
+    assert_eq!(glob("**/*.rs", &options), ["sub/c.rs"]);
+
+    options.dotglob = true;
+    assert_eq!(glob("*.rs", &options), [".hidden.rs", "a.rs", "b.rs"]);
+
+    options.dotglob = false;
+    options.globstar = true;
+    assert_eq!(
+        glob("**/*.rs", &options),
+        ["a.rs", "b.rs", "sub/c.rs", "sub/deep/d.rs"]
+    );
+
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -1,20 +1,39 @@
 use std::{
     env,
     fs::File,
-    io::{stderr, stdin, stdout, Read, Write},
-    path::PathBuf,
+    io::{self, stderr, BufRead, BufReader, Read, Write},
+    os::{fd::AsRawFd, unix::process::CommandExt},
+    path::{Path, PathBuf},
     process::Stdio,
     str::FromStr,
-    sync::Mutex,
+    sync::atomic::Ordering,
+    thread,
 };
 
 use std::process::Command as ProcessCommand;
 
+mod arith;
+mod braces;
+mod expand;
+mod glob;
 mod parser;
+mod redirect;
+use expand::ExpandError;
 use is_executable::is_executable;
-use parser::{Command as PCommand, CommandParser, Fd, RedirectTo, RedirectType};
+use parser::{
+    Command as PCommand, CommandList, CommandParser, Connector, ParseError, Pipeline, RedirectTo,
+    Word,
+};
+pub use redirect::describe as describe_error;
+pub use redirect::FdTable;
 
-use crate::history::History;
+use crate::{
+    history::History,
+    jobs::{self, Job, Process},
+    shell::Shell,
+    signals,
+    variables::{is_valid_name, Options},
+};
 
 #[derive(PartialEq)]
 enum InternalCommandName {
@@ -25,6 +44,20 @@ enum InternalCommandName {
     Exit,
     Pwd,
     History,
+    Exec,
+    Set,
+    Jobs,
+    Fg,
+    Bg,
+    Wait,
+    Disown,
+    Trap,
+    Source,
+    Export,
+    Unset,
+    Let,
+    /// `(( expression ))`
+    Arith,
 }
 
 impl FromStr for InternalCommandName {
@@ -39,6 +72,19 @@ impl FromStr for InternalCommandName {
             "exit" => Self::Exit,
             "pwd" => Self::Pwd,
             "history" => Self::History,
+            "exec" => Self::Exec,
+            "set" => Self::Set,
+            "jobs" => Self::Jobs,
+            "fg" => Self::Fg,
+            "bg" => Self::Bg,
+            "wait" => Self::Wait,
+            "disown" => Self::Disown,
+            "trap" => Self::Trap,
+            "source" | "." => Self::Source,
+            "export" => Self::Export,
+            "unset" => Self::Unset,
+            "let" => Self::Let,
+            "((" => Self::Arith,
             _ => return Err("nuh uh"),
         })
     }
@@ -47,22 +93,98 @@ impl FromStr for InternalCommandName {
 struct InternalCommand {
     name: InternalCommandName,
     args: Vec<String>,
+    // no builtin reads stdin yet, but holding it keeps a pipe's read end open until we finish
+    #[allow(dead_code)]
     input: Box<dyn Read + Send>,
     output: Box<dyn Write + Send>,
     error: Box<dyn Write + Send>,
+    /// every fd the builtin was given, including the ones above
+    fds: FdTable,
+    /// whether `fds` differ from the shell's, by redirections or pipes
+    redirected: bool,
+    /// part of a pipeline of more than one command
+    piped: bool,
+    /// assignments in front of it, handed on to the command `exec` runs
+    env: Vec<(String, String)>,
+}
+
+/// A parsed command after its words have been expanded and its redirections applied
+struct ExpandedCommand {
+    name: String,
+    args: Vec<String>,
+    fds: FdTable,
+    /// whether `fds` differ from the shell's, by redirections or pipes
+    redirected: bool,
+    /// part of a pipeline of more than one command
+    piped: bool,
+    /// variables set only in the command's environment
+    env: Vec<(String, String)>,
 }
 
-fn new_file(r_type: RedirectType, file_name: String) -> File {
-    File::options()
-        .append(matches!(r_type, RedirectType::Append))
-        .write(true)
-        .create(true)
-        .open(file_name)
-        .expect("Could not open file for redirection")
+impl ExpandedCommand {
+    /// `fds` holds the stdio the command gets before its own redirections, e.g. pipes.
+    /// Assignments without a command to go with are made to the shell's variables
+    fn new(
+        comm: PCommand,
+        mut fds: FdTable,
+        piped: bool,
+        shell: &'static Shell,
+    ) -> Result<Self, ExpandError> {
+        let mut words = expand::expand_words(&comm.words, shell)?.into_iter();
+        let name = words.next();
+
+        let mut env = vec![];
+        for (var, value) in &comm.assignments {
+            let value = expand::expand_quoted(value, shell)?;
+            match name {
+                Some(_) => env.push((var.clone(), value)),
+                None => shell.variables.lock().unwrap().set(var, value),
+            }
+        }
+
+        fds.apply(&comm.redirects, shell)?;
+
+        // without a command, the status is the one the last command substitution left,
+        // or 0 if there were none
+        if name.as_deref().unwrap_or_default().is_empty() {
+            let mut words = comm
+                .words
+                .iter()
+                .chain(comm.assignments.iter().map(|(_, value)| value))
+                .chain(comm.redirects.iter().filter_map(|r| match &r.to {
+                    RedirectTo::File(word) | RedirectTo::HereDoc(word) => Some(word),
+                    _ => None,
+                }));
+            if !words.any(Word::has_substitution) {
+                shell.variables.lock().unwrap().last_status = 0;
+            }
+        }
+
+        Ok(ExpandedCommand {
+            name: name.unwrap_or_default(),
+            args: words.collect(),
+            fds,
+            redirected: piped || !comm.redirects.is_empty(),
+            piped,
+            env,
+        })
+    }
 }
 
-fn find_in_path(comm: &str) -> Option<PathBuf> {
-    for path in env::split_paths(&env::var_os("PATH").unwrap()) {
+/// Finds the file `source` reads: names with a slash are used as is, others are searched
+/// for in PATH and then the current directory
+fn find_source(name: &str, path: &str) -> Option<PathBuf> {
+    if name.contains('/') {
+        return Some(name.into());
+    }
+    env::split_paths(path)
+        .map(|path| path.join(name))
+        .find(|joined| joined.is_file())
+        .or_else(|| Path::new(name).is_file().then(|| name.into()))
+}
+
+fn find_in_path(comm: &str, path: &str) -> Option<PathBuf> {
+    for path in env::split_paths(path) {
         let joined = path.join(comm);
         if joined.is_file() && is_executable(&joined) {
             return Some(joined);
@@ -72,70 +194,91 @@ fn find_in_path(comm: &str) -> Option<PathBuf> {
 }
 
 impl InternalCommand {
-    fn from_parsed_command(comm: PCommand) -> Result<Self, PCommand> {
+    fn from_parsed_command(comm: ExpandedCommand) -> Result<Self, ExpandedCommand> {
         let name = match comm.name.parse() {
             Ok(n) => n,
             Err(_) => return Err(comm),
         };
 
-        let (output, error): (Box<dyn Write + Send>, Box<dyn Write + Send>) = match comm.redirect {
-            None => (Box::new(stdout()), Box::new(stderr())),
-            Some(r) => {
-                if let RedirectTo::File(file_name) = r.to {
-                    let file = new_file(r.r_type, file_name);
-                    match r.from {
-                        Fd::Stdout => (Box::new(file), Box::new(stderr())),
-                        Fd::Stderr => (Box::new(stdout()), Box::new(file)),
-                        _ => unimplemented!("Internal commands do not work with arbitrary fds"),
-                    }
-                } else {
-                    unimplemented!("Only redirections to files are supported atm")
-                }
-            }
-        };
-
         Ok(InternalCommand {
             name,
             args: comm.args,
-            input: Box::new(stdin()),
-            output,
-            error,
+            input: comm.fds.input(0),
+            output: comm.fds.output(1),
+            error: comm.fds.output(2),
+            fds: comm.fds,
+            redirected: comm.redirected,
+            piped: comm.piped,
+            env: comm.env,
         })
     }
 
-    fn run(mut self, history: &Mutex<History>) {
+    /// The command `exec` runs, with the fds and environment `exec` was given
+    fn exec_target(mut self) -> ExpandedCommand {
+        ExpandedCommand {
+            name: self.args.remove(0),
+            args: self.args,
+            fds: self.fds,
+            redirected: self.redirected,
+            piped: self.piped,
+            env: self.env,
+        }
+    }
+
+    /// Runs the builtin, returning its exit status
+    fn run(mut self, shell: &'static Shell) -> i32 {
         match self.name {
             InternalCommandName::Echo => {
-                let _ = writeln!(self.output, "{}", self.args.join(" "));
-            }
-            InternalCommandName::Type => {
-                let _ = match self.args.first().map(String::as_str) {
-                    Some(comm @ ("echo" | "cd" | "type" | "exit" | "pwd" | "history")) => {
-                        writeln!(self.output, "{comm} is a shell builtin")
-                    }
-                    Some(comm) => match find_in_path(comm) {
-                        Some(full_path) => {
-                            writeln!(self.output, "{comm} is {}", full_path.display())
-                        }
-                        None => writeln!(self.error, "{comm}: not found"),
-                    },
-                    None => writeln!(self.error, "Expected an arguement"),
-                };
+                if let Err(e) = writeln!(self.output, "{}", self.args.join(" ")) {
+                    let _ = writeln!(self.error, "echo: write error: {}", describe_error(&e));
+                    return 1;
+                }
+                0
             }
+            InternalCommandName::Type => match self.args.first().map(String::as_str) {
+                Some(comm)
+                    if comm
+                        .parse::<InternalCommandName>()
+                        .is_ok_and(|name| name != InternalCommandName::Empty) =>
+                {
+                    let _ = writeln!(self.output, "{comm} is a shell builtin");
+                    0
+                }
+                Some(comm) => match find_in_path(comm, &shell.path()) {
+                    Some(full_path) => {
+                        let _ = writeln!(self.output, "{comm} is {}", full_path.display());
+                        0
+                    }
+                    None => {
+                        let _ = writeln!(self.error, "{comm}: not found");
+                        1
+                    }
+                },
+                None => {
+                    let _ = writeln!(self.error, "Expected an arguement");
+                    1
+                }
+            },
             InternalCommandName::Pwd => {
                 if !self.args.is_empty() {
                     let _ = writeln!(self.error, "expected 0 arguments; got {}", self.args.len());
-                    return;
+                    return 1;
+                }
+                match env::current_dir() {
+                    Ok(path) => {
+                        let _ = writeln!(self.output, "{}", path.display());
+                        0
+                    }
+                    _ => {
+                        let _ = writeln!(self.error, "Current directory cannot be found!");
+                        1
+                    }
                 }
-                let _ = match env::current_dir() {
-                    Ok(path) => writeln!(self.output, "{}", path.display()),
-                    _ => writeln!(self.error, "Current directory cannot be found!"),
-                };
             }
             InternalCommandName::Cd => {
                 if self.args.len() > 1 {
                     let _ = writeln!(self.error, "Too many args for cd command");
-                    return;
+                    return 1;
                 }
 
                 if self.args.is_empty() {
@@ -152,22 +295,24 @@ impl InternalCommand {
 
                 if env::set_current_dir(&path_str).is_err() {
                     let _ = writeln!(self.error, "cd: {}: No such file or directory", path_str);
+                    return 1;
                 }
+                0
             }
             InternalCommandName::History => {
-                let mut history = history.lock().unwrap();
-                let _ = match self.args.first().map(String::as_str) {
+                let mut history = shell.history.lock().unwrap();
+                let res = match self.args.first().map(String::as_str) {
                     None => history.write(&mut self.output, None),
                     Some("-r") => {
                         let Some(path) = self.args.get(1) else {
                             let _ =
                                 writeln!(self.error, "history -r: Expected <path_to_history_file>");
-                            return;
+                            return 1;
                         };
 
                         let Some(file_history) = History::from_file(path.into()) else {
                             let _ = writeln!(self.error, "history -r {path}: Could not read file");
-                            return;
+                            return 1;
                         };
 
                         *history += file_history;
@@ -180,7 +325,7 @@ impl InternalCommand {
                                 self.error,
                                 "history {arg}: Expected <path_to_history_file>"
                             );
-                            return;
+                            return 1;
                         };
 
                         if let Err(e) = history.write_to_file(path.into(), arg == "-a") {
@@ -189,7 +334,7 @@ impl InternalCommand {
                                 "history {arg} {path}: Could not create/write file - {}",
                                 e
                             );
-                            return;
+                            return 1;
                         };
 
                         Ok(())
@@ -197,141 +342,820 @@ impl InternalCommand {
                     Some(arg) => {
                         let Ok(limit) = arg.parse::<usize>() else {
                             let _ = writeln!(self.error, "history {}: Invalid option", arg);
-                            return;
+                            return 1;
                         };
 
                         history.write(&mut self.output, Some(limit))
                     }
                 };
+                i32::from(res.is_err())
+            }
+            InternalCommandName::Exit => match self.args.first() {
+                None => shell.variables.lock().unwrap().last_status,
+                // statuses wrap around like they would for a real process
+                Some(arg) => match arg.parse::<i64>() {
+                    Ok(_) if self.args.len() > 1 => {
+                        let _ = writeln!(self.error, "exit: too many arguments");
+                        1
+                    }
+                    Ok(n) => (n & 0xff) as i32,
+                    Err(_) => {
+                        let _ = writeln!(self.error, "exit: {arg}: numeric argument required");
+                        2
+                    }
+                },
+            },
+            InternalCommandName::Source => {
+                if self.args.is_empty() {
+                    let _ = writeln!(self.error, "source: filename argument required");
+                    return 2;
+                }
+                let name = self.args.remove(0);
+                let file = find_source(&name, &shell.path())
+                    .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))
+                    .and_then(File::open);
+                let file = match file {
+                    Ok(file) => file,
+                    Err(e) => {
+                        let _ = writeln!(self.error, "source: {name}: {}", describe_error(&e));
+                        return 1;
+                    }
+                };
+
+                // the arguments stand in for the positional parameters while it runs
+                let saved_positional = (!self.args.is_empty()).then(|| {
+                    let mut vars = shell.variables.lock().unwrap();
+                    std::mem::replace(&mut vars.positional, self.args)
+                });
+                // so do the redirections of `source` for the shell's fds. Without any, the
+                // file works on the shell's own, and an `exec 3>log` in it has to last
+                let saved_fds = self
+                    .redirected
+                    .then(|| std::mem::replace(&mut *shell.fds.lock().unwrap(), self.fds));
+
+                let result = run_lines(&name, BufReader::new(file), shell);
+
+                if let Some(saved_fds) = saved_fds {
+                    *shell.fds.lock().unwrap() = saved_fds;
+                }
+                if let Some(positional) = saved_positional {
+                    shell.variables.lock().unwrap().positional = positional;
+                }
+                if let RunResult::Exit(_) = result {
+                    shell.exit_requested.store(true, Ordering::Relaxed);
+                }
+                result.status()
+            }
+            InternalCommandName::Export => {
+                let mut vars = shell.variables.lock().unwrap();
+                let mut args = self.args.iter().peekable();
+                let mut export = true;
+                match args.peek().map(|a| a.as_str()) {
+                    None | Some("-p") => {
+                        for (name, value) in vars.exported_sorted() {
+                            let _ = match value {
+                                Some(value) => {
+                                    // quoted so the line can be read back in
+                                    let mut quoted = String::new();
+                                    for c in value.chars() {
+                                        if matches!(c, '\\' | '"' | '$' | '`') {
+                                            quoted.push('\\');
+                                        }
+                                        quoted.push(c);
+                                    }
+                                    writeln!(self.output, "declare -x {name}=\"{quoted}\"")
+                                }
+                                None => writeln!(self.output, "declare -x {name}"),
+                            };
+                        }
+                        return 0;
+                    }
+                    Some("-n") => {
+                        args.next();
+                        export = false;
+                    }
+                    _ => {}
+                }
+
+                let mut status = 0;
+                for arg in args {
+                    let (name, value) = match arg.split_once('=') {
+                        Some((name, value)) => (name, Some(value)),
+                        None => (arg.as_str(), None),
+                    };
+                    if !is_valid_name(name) {
+                        let _ = writeln!(self.error, "export: `{arg}': not a valid identifier");
+                        status = 1;
+                        continue;
+                    }
+                    if let Some(value) = value {
+                        vars.set(name, value.to_owned());
+                    }
+                    if export {
+                        vars.export(name);
+                    } else {
+                        vars.unexport(name);
+                    }
+                }
+                status
+            }
+            InternalCommandName::Let | InternalCommandName::Arith => {
+                let name = match self.name {
+                    InternalCommandName::Let => "let",
+                    _ => "((",
+                };
+                if self.args.is_empty() {
+                    let _ = writeln!(self.error, "{name}: expression expected");
+                    return 1;
+                }
+                let mut value = 0;
+                for expr in &self.args {
+                    let mut vars = shell.variables.lock().unwrap();
+                    match arith::eval(expr, &mut vars) {
+                        Ok(v) => value = v,
+                        Err(e) => {
+                            let _ = writeln!(self.error, "{name}: {e}");
+                            return 1;
+                        }
+                    }
+                }
+                // true when the last expression is non-zero
+                (value == 0) as i32
+            }
+            InternalCommandName::Unset => {
+                let mut vars = shell.variables.lock().unwrap();
+                let names = match self.args.first().map(String::as_str) {
+                    Some("-v") => &self.args[1..],
+                    _ => &self.args[..],
+                };
+                let mut status = 0;
+                for name in names {
+                    if !is_valid_name(name) {
+                        let _ = writeln!(self.error, "unset: `{name}': not a valid identifier");
+                        status = 1;
+                        continue;
+                    }
+                    vars.unset(name);
+                }
+                status
+            }
+            InternalCommandName::Exec => {
+                if self.args.is_empty() {
+                    // no command, the redirections apply to the shell itself. Not in a
+                    // pipeline though, where they only last as long as the pipeline
+                    if !self.piped {
+                        *shell.fds.lock().unwrap() = self.fds;
+                    }
+                    return 0;
+                }
+
+                // the command takes the rest of the builtin, errors still go here
+                let mut error = std::mem::replace(&mut self.error, Box::new(io::sink()));
+                let mut external = ExternalCommand::from_parsed_command(self.exec_target(), shell);
+                if !external.found {
+                    let _ = writeln!(error, "exec: {}: not found", external.name);
+                    return 127;
+                }
+                // only returns if the process couldn't replace us
+                let e = external.process.exec();
+                let _ = writeln!(error, "exec: {}: {}", external.name, describe_error(&e));
+                not_executable_status(&e)
+            }
+            InternalCommandName::Set => {
+                let mut vars = shell.variables.lock().unwrap();
+                let mut args = self.args.iter();
+                while let Some(arg) = args.next() {
+                    let enable = match arg.chars().next() {
+                        Some('-') => true,
+                        Some('+') => false,
+                        _ => {
+                            let _ = writeln!(self.error, "set: {arg}: invalid option");
+                            return 2;
+                        }
+                    };
+                    let name = match &arg[1..] {
+                        "C" => "noclobber",
+                        "o" => match args.next() {
+                            Some(name) => name,
+                            None => {
+                                // list every option and whether it is on
+                                for name in Options::names() {
+                                    let on = *vars.options.by_name(name).unwrap();
+                                    let state = if on { "on" } else { "off" };
+                                    let _ = writeln!(self.output, "{name:<15}\t{state}");
+                                }
+                                continue;
+                            }
+                        },
+                        _ => {
+                            let _ = writeln!(self.error, "set: {arg}: invalid option");
+                            return 2;
+                        }
+                    };
+                    match vars.options.by_name(name) {
+                        Some(option) => *option = enable,
+                        None => {
+                            let _ = writeln!(self.error, "set: {name}: invalid option name");
+                            return 2;
+                        }
+                    }
+                }
+                0
+            }
+            InternalCommandName::Jobs => {
+                let mut jobs = shell.jobs.lock().unwrap();
+                let res = match self.args.first().map(String::as_str) {
+                    None => jobs.list(&mut self.output, false),
+                    Some("-l") => jobs.list(&mut self.output, true),
+                    Some("-p") => jobs.list_pids(&mut self.output),
+                    Some(arg) => {
+                        let _ = writeln!(self.error, "jobs: {arg}: invalid option");
+                        return 2;
+                    }
+                };
+                if res.is_err() {
+                    return 1;
+                }
+                0
+            }
+            InternalCommandName::Fg | InternalCommandName::Bg => {
+                let name = if self.name == InternalCommandName::Fg {
+                    "fg"
+                } else {
+                    "bg"
+                };
+                if !shell.job_control {
+                    let _ = writeln!(self.error, "{name}: no job control");
+                    return 1;
+                }
+                let found = shell
+                    .jobs
+                    .lock()
+                    .unwrap()
+                    .find(self.args.first().map(String::as_str));
+                let index = match found {
+                    Ok(index) => index,
+                    Err(e) => {
+                        let _ = writeln!(self.error, "{name}: {e}");
+                        return 1;
+                    }
+                };
+
+                if self.name == InternalCommandName::Bg {
+                    return match jobs::background(shell, index) {
+                        Ok(line) => {
+                            let _ = writeln!(self.output, "{line}");
+                            0
+                        }
+                        Err(e) => {
+                            let _ = writeln!(self.error, "bg: {e}");
+                            1
+                        }
+                    };
+                }
+                let job = shell.jobs.lock().unwrap().remove(index);
+                let _ = writeln!(self.output, "{}", job.command);
+                jobs::foreground(shell, job)
+            }
+            InternalCommandName::Wait => {
+                let Some(spec) = self.args.first() else {
+                    // every job that is still running
+                    loop {
+                        let jobs = shell.jobs.lock().unwrap();
+                        let Some(index) = jobs.find_running() else {
+                            return 0;
+                        };
+                        drop(jobs);
+                        jobs::wait(shell, index);
+                    }
+                };
+
+                let jobs = shell.jobs.lock().unwrap();
+                let found = match spec.parse() {
+                    Ok(pid) if !spec.starts_with('%') => jobs
+                        .find_pid(pid)
+                        .ok_or_else(|| format!("pid {pid} is not a child of this shell")),
+                    _ => jobs.find(Some(spec)),
+                };
+                drop(jobs);
+                match found {
+                    Ok(index) => jobs::wait(shell, index),
+                    Err(e) => {
+                        let _ = writeln!(self.error, "wait: {e}");
+                        127
+                    }
+                }
+            }
+            InternalCommandName::Disown => {
+                let mut jobs = shell.jobs.lock().unwrap();
+                match jobs.find(self.args.first().map(String::as_str)) {
+                    Ok(index) => {
+                        jobs.remove(index);
+                        0
+                    }
+                    Err(e) => {
+                        let _ = writeln!(self.error, "disown: {e}");
+                        1
+                    }
+                }
+            }
+            InternalCommandName::Trap => {
+                let mut traps = shell.traps.lock().unwrap();
+                // `--` ends the options, as in what `trap -p` prints
+                let args = match self.args.first().map(String::as_str) {
+                    Some("--") => &self.args[1..],
+                    _ => &self.args[..],
+                };
+                let (command, specs) = match args.first().map(String::as_str) {
+                    None | Some("-p") => {
+                        for line in traps.list() {
+                            let _ = writeln!(self.output, "{line}");
+                        }
+                        return 0;
+                    }
+                    Some("-l") => {
+                        for line in signals::list() {
+                            let _ = writeln!(self.output, "{line}");
+                        }
+                        return 0;
+                    }
+                    // a signal on its own is reset
+                    Some(spec) if args.len() == 1 && signals::parse(spec).is_some() => (None, args),
+                    Some(_) if args.len() == 1 => {
+                        let _ = writeln!(
+                            self.error,
+                            "trap: usage: trap [-lp] [[arg] signal_spec ...]"
+                        );
+                        return 2;
+                    }
+                    Some("-") => (None, &args[1..]),
+                    Some(command) => (Some(command), &args[1..]),
+                };
+
+                let mut status = 0;
+                for spec in specs {
+                    let Some(sig) = signals::parse(spec) else {
+                        let _ = writeln!(self.error, "trap: {spec}: invalid signal specification");
+                        status = 1;
+                        continue;
+                    };
+                    match command {
+                        Some(command) => traps.set(sig, command.to_owned()),
+                        None => traps.reset(sig, shell.job_control),
+                    }
+                }
+                status
             }
-            InternalCommandName::Exit => {}
-            InternalCommandName::Empty => {}
+            InternalCommandName::Empty => shell.variables.lock().unwrap().last_status,
         }
     }
 }
 
 struct ExternalCommand {
+    /// the name it was run by
+    name: String,
+    /// false if it wasn't found in PATH, the process is never started then
+    found: bool,
     process: ProcessCommand,
 }
 
 impl ExternalCommand {
-    fn from_parsed_command(comm: PCommand) -> Self {
-        let mut process = ProcessCommand::new(comm.name);
-        process.args(comm.args);
-
-        if let Some(r) = comm.redirect {
-            let RedirectTo::File(file_name) = r.to else {
-                // pre_exec could be used here
-                unimplemented!("Only redirections to files are supported atm")
-            };
-            let file = new_file(r.r_type, file_name);
-            match r.from {
-                Fd::Stdout => {
-                    process.stdout(Stdio::from(file));
-                }
-                Fd::Stderr => {
-                    process.stderr(Stdio::from(file));
+    fn from_parsed_command(mut comm: ExpandedCommand, shell: &Shell) -> Self {
+        let vars = shell.variables.lock().unwrap();
+        // looked up in the shell's PATH, not the one it was started with
+        let program = if comm.name.contains('/') {
+            Some(PathBuf::from(&comm.name))
+        } else {
+            find_in_path(&comm.name, &vars.get("PATH").unwrap_or_default())
+        };
+        let found = program.is_some();
+
+        let mut process = ProcessCommand::new(program.unwrap_or_default());
+        process.arg0(&comm.name).args(comm.args);
+        // only exported variables make it into the environment, with the command's own
+        // assignments on top
+        process
+            .env_clear()
+            .envs(vars.exported())
+            .envs(comm.env.iter().map(|(k, v)| (k, v)));
+        drop(vars);
+
+        let mut closed = vec![];
+        for fd in 0..=2 {
+            let stdio = match comm.fds.take(fd) {
+                Some(fd) => Stdio::from(fd),
+                None => {
+                    closed.push(fd);
+                    Stdio::null()
                 }
-                _ => unimplemented!("Internal commands do not work with arbitrary fds"),
+            };
+            match fd {
+                0 => process.stdin(stdio),
+                1 => process.stdout(stdio),
+                _ => process.stderr(stdio),
+            };
+        }
+        let others = comm.fds.take_others();
+        if !closed.is_empty() || !others.is_empty() {
+            // anything at or above this is free to use without clobbering a target fd
+            let scratch = others
+                .iter()
+                .flat_map(|(to, fd)| [*to, fd.as_raw_fd()])
+                .max()
+                .unwrap_or(2)
+                + 1;
+            // allocated up front, the hook runs after fork so it must not allocate
+            let mut moved = Vec::with_capacity(others.len());
+
+            // stdio is set up before this hook runs, so the fds can be closed after the fact
+            unsafe {
+                process.pre_exec(move || {
+                    for fd in &closed {
+                        libc::close(*fd);
+                    }
+
+                    // move every source out of the way first, so that setting one target
+                    // can't clobber the source of another
+                    moved.clear();
+                    for (_, fd) in &others {
+                        let new_fd = libc::fcntl(fd.as_raw_fd(), libc::F_DUPFD_CLOEXEC, scratch);
+                        if new_fd == -1 {
+                            return Err(io::Error::last_os_error());
+                        }
+                        moved.push(new_fd);
+                    }
+                    for ((to, _), fd) in others.iter().zip(&moved) {
+                        // the copy dup2 makes does not close on exec
+                        if libc::dup2(*fd, *to) == -1 {
+                            return Err(io::Error::last_os_error());
+                        }
+                    }
+                    Ok(())
+                });
             }
         }
 
-        ExternalCommand { process }
+        let reset = signals::to_reset(shell.job_control, &shell.traps.lock().unwrap());
+        unsafe {
+            process.pre_exec(move || {
+                signals::reset_in_child(&reset);
+                Ok(())
+            });
+        }
+
+        ExternalCommand {
+            name: comm.name,
+            found,
+            process,
+        }
     }
 
-    fn run(mut self) {
+    /// Starts the process without waiting for it, in the process group `pgid` if given.
+    /// A pgid of 0 starts a new group led by the process
+    fn spawn(mut self, pgid: Option<u32>) -> Process {
+        if let Some(pgid) = pgid {
+            self.process.process_group(pgid as i32);
+        }
+        if !self.found {
+            let _ = writeln!(stderr(), "{}: command not found", self.name);
+            return Process::done(127);
+        }
         match self.process.spawn() {
-            Ok(mut child) => {
-                child.wait().expect("command wasn't running");
-            }
-            Err(_) => {
-                let _ = writeln!(
-                    stderr(),
-                    "{}: command not found",
-                    self.process.get_program().to_str().unwrap()
-                );
+            Ok(child) => Process::child(child.id()),
+            Err(e) => {
+                let _ = writeln!(stderr(), "shell: {}: {}", self.name, describe_error(&e));
+                Process::done(not_executable_status(&e))
             }
-        };
+        }
+    }
+}
+
+/// 127 for a program that doesn't exist, 126 for one that can't be run
+fn not_executable_status(e: &io::Error) -> i32 {
+    if e.kind() == io::ErrorKind::NotFound {
+        127
+    } else {
+        126
     }
 }
 
 enum Command {
     Internal(InternalCommand),
     External(ExternalCommand),
+    /// its redirections could not be set up, so it is never run
+    Failed,
 }
 
+/// Both variants carry the exit status of the last pipeline that ran
 #[derive(PartialEq)]
 pub enum RunResult {
-    Exit,
-    Continue,
+    Exit(i32),
+    Continue(i32),
+}
+
+impl RunResult {
+    pub fn status(&self) -> i32 {
+        match self {
+            RunResult::Exit(status) | RunResult::Continue(status) => *status,
+        }
+    }
+}
+
+/// true if the input needs more lines before it can run, e.g. an open here-document or
+/// quote, or a line ending in `&&`
+pub fn is_incomplete(input: &str) -> bool {
+    match CommandParser::new(input).parse() {
+        Err(ParseError::UnterminatedHereDoc { .. }) => true,
+        Err(ParseError::UnexpectedEnd { offset, .. }) => offset == input.len(),
+        _ => false,
+    }
 }
 
-pub fn run_from_history(history: &Mutex<History>) -> RunResult {
+pub fn run_from_history(shell: &'static Shell) -> RunResult {
     // input retrieved from end of history
-    let binding = history.lock().unwrap();
-    let input = binding.last().unwrap();
-    let parsed_commands = CommandParser::new(input).parse();
-    drop(binding);
+    let input = shell.history.lock().unwrap().last().unwrap().clone();
+    run(&input, shell)
+}
+
+/// Runs the trap commands of the signals that arrived since the last call
+pub fn run_pending_traps(shell: &'static Shell) -> RunResult {
+    for sig in signals::take_pending() {
+        let command = shell.traps.lock().unwrap().get(sig).map(str::to_owned);
+        if let Some(command) = command {
+            if let exit @ RunResult::Exit(_) = run_trap(&command, shell) {
+                return exit;
+            }
+        }
+    }
+    RunResult::Continue(shell.variables.lock().unwrap().last_status)
+}
 
-    if parsed_commands.is_empty() {
-        return RunResult::Continue;
+/// Runs the EXIT trap, if there is one. The shell exits with `status` unless the trap
+/// calls `exit` itself
+pub fn run_exit_trap(shell: &'static Shell, status: i32) -> i32 {
+    let command = shell
+        .traps
+        .lock()
+        .unwrap()
+        .get(signals::EXIT)
+        .map(str::to_owned);
+    match command.map(|command| run_trap(&command, shell)) {
+        Some(RunResult::Exit(status)) => status,
+        _ => status,
     }
+}
 
-    let mut res = RunResult::Continue;
+/// Runs a trap command, which leaves `$?` as it was unless it exits the shell
+fn run_trap(command: &str, shell: &'static Shell) -> RunResult {
+    let status = shell.variables.lock().unwrap().last_status;
+    match run(command, shell) {
+        RunResult::Continue(_) => {
+            shell.variables.lock().unwrap().last_status = status;
+            RunResult::Continue(status)
+        }
+        exit => exit,
+    }
+}
 
-    let mut compiled_commands: Vec<_> = parsed_commands
-        .into_iter()
-        .map(|p_c| match InternalCommand::from_parsed_command(p_c) {
-            Ok(internal_comm) => {
-                if internal_comm.name == InternalCommandName::Exit {
-                    res = RunResult::Exit;
-                };
-                Command::Internal(internal_comm)
+/// Parses and runs a command list
+pub fn run(input: &str, shell: &'static Shell) -> RunResult {
+    match CommandParser::new(input).parse() {
+        Ok(list) => run_list(list, shell),
+        Err(e) => {
+            let _ = writeln!(stderr(), "{e}");
+            shell.variables.lock().unwrap().last_status = 2;
+            RunResult::Continue(2)
+        }
+    }
+}
+
+/// Runs a command substitution: `input` runs with its stdout going to a pipe, and what it
+/// wrote is returned without trailing newlines. Like a subshell, changes it makes to the
+/// shell's variables, fds and working directory don't last, though `$?` is left as its
+/// status
+pub fn capture_output(input: &str, shell: &'static Shell) -> String {
+    let list = match CommandParser::new(input).parse() {
+        Ok(list) => list,
+        Err(e) => {
+            let _ = writeln!(stderr(), "{e}");
+            shell.variables.lock().unwrap().last_status = 2;
+            return String::new();
+        }
+    };
+
+    let (mut reader, writer) = os_pipe::pipe().unwrap();
+    let mut fds = shell
+        .fds
+        .lock()
+        .unwrap()
+        .try_clone()
+        .expect("could not duplicate the shell's fds");
+    fds.set(1, writer);
+    // read on another thread so output bigger than the pipe can't block the commands
+    let output = thread::spawn(move || {
+        let mut output = vec![];
+        let _ = reader.read_to_end(&mut output);
+        output
+    });
+
+    let saved_fds = std::mem::replace(&mut *shell.fds.lock().unwrap(), fds);
+    let saved_vars = shell.variables.lock().unwrap().clone();
+    let saved_dir = env::current_dir();
+
+    let status = run_list(list, shell).status();
+
+    // putting the shell's fds back closes the last write end the shell held
+    drop(std::mem::replace(
+        &mut *shell.fds.lock().unwrap(),
+        saved_fds,
+    ));
+    *shell.variables.lock().unwrap() = saved_vars;
+    shell.variables.lock().unwrap().last_status = status;
+    if let Ok(dir) = saved_dir {
+        let _ = env::set_current_dir(dir);
+    }
+    shell.exit_requested.store(false, Ordering::Relaxed);
+
+    let output = String::from_utf8_lossy(&output.join().unwrap()).into_owned();
+    output.trim_end_matches('\n').to_owned()
+}
+
+/// Runs commands read line by line, e.g. from a script. A syntax error is reported with
+/// `name` and the line it is on, and stops the rest from running with a status of 2
+pub fn run_lines(name: &str, reader: impl BufRead, shell: &'static Shell) -> RunResult {
+    let mut input = String::new();
+    // the line `input` starts on
+    let mut start = 0;
+
+    let mut lines = reader.lines().enumerate();
+    loop {
+        let line = lines.next();
+        match line {
+            Some((number, Ok(line))) => {
+                if input.is_empty() {
+                    start = number + 1;
+                } else {
+                    input.push('\n');
+                }
+                input.push_str(&line);
+                // keep reading lines until the command is complete, e.g. every here-document is closed
+                if is_incomplete(&input) {
+                    continue;
+                }
+            }
+            Some((_, Err(e))) => {
+                let _ = writeln!(stderr(), "{name}: {}", describe_error(&e));
+                return RunResult::Continue(1);
+            }
+            // whatever is left is incomplete, which parsing will report
+            None if !input.is_empty() => {}
+            None => break,
+        }
+
+        let list = match CommandParser::new(&input).parse() {
+            Ok(list) => list,
+            Err(e) => {
+                // an offset that is off shouldn't take the shell down with it
+                let line = start
+                    + input
+                        .get(..e.offset())
+                        .map_or(0, |before| before.matches('\n').count());
+                let _ = writeln!(stderr(), "{name}: line {line}: {e}");
+                shell.variables.lock().unwrap().last_status = 2;
+                return RunResult::Continue(2);
             }
-            Err(p_comm) => Command::External(ExternalCommand::from_parsed_command(p_comm)),
+        };
+        input.clear();
+
+        if let exit @ RunResult::Exit(_) = run_list(list, shell) {
+            return exit;
+        }
+    }
+
+    RunResult::Continue(shell.variables.lock().unwrap().last_status)
+}
+
+fn run_list(command_list: CommandList, shell: &'static Shell) -> RunResult {
+    let mut status = shell.variables.lock().unwrap().last_status;
+    for (connector, pipeline) in command_list {
+        let should_run = match connector {
+            Connector::Seq => true,
+            Connector::And => status == 0,
+            Connector::Or => status != 0,
+        };
+        if !should_run {
+            continue;
+        }
+
+        match run_pipeline(pipeline, shell) {
+            RunResult::Continue(s) => status = s,
+            exit => return exit,
+        }
+        // traps run between pipelines, not only once the whole line is done
+        if let exit @ RunResult::Exit(_) = run_pending_traps(shell) {
+            return exit;
+        }
+    }
+
+    RunResult::Continue(status)
+}
+
+fn run_pipeline(pipeline: Pipeline, shell: &'static Shell) -> RunResult {
+    let mut exit = false;
+    let parsed_commands = pipeline.commands;
+
+    // pipe each pair of adjacent commands together, before their own redirections apply
+    let shell_fds = shell.fds.lock().unwrap();
+    let mut stdio: Vec<_> = parsed_commands
+        .iter()
+        .map(|_| {
+            shell_fds
+                .try_clone()
+                .expect("could not duplicate the shell's fds")
         })
         .collect();
+    drop(shell_fds);
+    let piped = stdio.len() > 1;
+    for i in 0..stdio.len() - 1 {
+        let (reader, writer) = os_pipe::pipe().unwrap();
 
-    // pipe each pair of adjacent commands together
-    for i in 0..compiled_commands.len() - 1 {
-        match &mut compiled_commands[i..i + 2] {
-            [Command::External(e_1), Command::External(e_2)] => {
-                let (reader, writer) = os_pipe::pipe().unwrap();
+        stdio[i].set(1, writer);
+        stdio[i + 1].set(0, reader);
+    }
 
-                e_1.process.stdout(writer);
-                e_2.process.stdin(reader);
+    let compiled_commands: Vec<_> = parsed_commands
+        .into_iter()
+        .zip(stdio)
+        .map(|(p_c, fds)| ExpandedCommand::new(p_c, fds, piped, shell))
+        .map(|e_c| match e_c.map(InternalCommand::from_parsed_command) {
+            // `exec` can't replace the shell from inside a pipeline, the command runs like
+            // any other in its place
+            Ok(Ok(internal_comm))
+                if internal_comm.name == InternalCommandName::Exec
+                    && piped
+                    && !internal_comm.args.is_empty() =>
+            {
+                Command::External(ExternalCommand::from_parsed_command(
+                    internal_comm.exec_target(),
+                    shell,
+                ))
             }
-            [Command::External(ec), Command::Internal(ic)] => {
-                let (reader, writer) = os_pipe::pipe().unwrap();
-
-                ec.process.stdout(writer);
-                ic.input = Box::new(reader);
+            Ok(Ok(internal_comm)) => {
+                // like bash, a pipeline only exits the shell when `exit` is all there is,
+                // and `exit 1 2` complains instead
+                if internal_comm.name == InternalCommandName::Exit
+                    && !piped
+                    && !(internal_comm.args.len() > 1
+                        && internal_comm.args[0].parse::<i64>().is_ok())
+                {
+                    exit = true;
+                };
+                Command::Internal(internal_comm)
             }
-            [Command::Internal(ic), Command::External(ec)] => {
-                let (reader, writer) = os_pipe::pipe().unwrap();
-
-                ic.output = Box::new(writer);
-                ec.process.stdin(reader);
+            Ok(Err(e_comm)) => {
+                Command::External(ExternalCommand::from_parsed_command(e_comm, shell))
             }
-            [Command::Internal(i_1), Command::Internal(i_2)] => {
-                let (reader, writer) = pipe::pipe();
+            Err(e) => {
+                eprintln!("shell: {e}");
+                Command::Failed
+            }
+        })
+        .collect();
 
-                i_1.output = Box::new(writer);
-                i_2.input = Box::new(reader);
+    // externals are started as child processes and builtins on their own threads
+    // with job control the first child leads a process group the others join
+    let mut pgid = None;
+    let processes: Vec<_> = compiled_commands
+        .into_iter()
+        .map(|comm| match comm {
+            Command::External(e) => {
+                let process = e.spawn(shell.job_control.then_some(pgid.unwrap_or(0)));
+                pgid = pgid.or(process.pid);
+                process
             }
-            _ => unreachable!(),
-        }
+            Command::Internal(i) => Process::builtin(thread::spawn(move || i.run(shell))),
+            Command::Failed => Process::done(1),
+        })
+        .collect();
+    let job = Job::new(pipeline.source, processes, pgid);
+
+    if pipeline.background {
+        let mut jobs = shell.jobs.lock().unwrap();
+        let job = jobs.add(job);
+        // builtins run inside the shell, so a job of only builtins goes by the shell's pid
+        let pid = job.pid().unwrap_or_else(std::process::id);
+        let _ = writeln!(stderr(), "[{}] {pid}", job.id);
+        shell.variables.lock().unwrap().last_background_pid = Some(pid);
+        return RunResult::Continue(0);
     }
 
-    // run commands on separate threads
-    std::thread::scope(|s| {
-        for comm in compiled_commands {
-            s.spawn(|| match comm {
-                Command::External(e) => e.run(),
-                Command::Internal(i) => i.run(history),
-            });
-        }
-    });
+    // the pipeline's status is that of the last command
+    let status = jobs::foreground(shell, job);
+    shell.variables.lock().unwrap().last_status = status;
 
-    res
+    if exit || shell.exit_requested.swap(false, Ordering::Relaxed) {
+        RunResult::Exit(status)
+    } else {
+        RunResult::Continue(status)
+    }
 }
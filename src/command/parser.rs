@@ -4,6 +4,10 @@ use std::{
     str::{Chars, FromStr},
 };
 
+use thiserror::Error;
+
+use crate::variables::is_valid_name;
+
 #[derive(Debug)]
 pub enum Fd {
     Stdin,
@@ -20,6 +24,7 @@ impl FromStr for Fd {
             Self::Stdout
         } else {
             match i32::from_str(s)? {
+                0 => Self::Stdin,
                 1 => Self::Stdout,
                 2 => Self::Stderr,
                 oth => Self::Other(oth),
@@ -28,16 +33,37 @@ impl FromStr for Fd {
     }
 }
 
+impl Fd {
+    pub fn raw(&self) -> i32 {
+        match self {
+            Self::Stdin => 0,
+            Self::Stdout => 1,
+            Self::Stderr => 2,
+            Self::Other(fd) => *fd,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum RedirectTo {
-    File(String),
+    File(Word),
+    /// `N>&M`, makes N a copy of M
     Fd(Fd),
+    /// `N>&-`
+    Close,
+    /// `<< DELIM` and `<<< word`, the expanded word is what gets read from the fd
+    HereDoc(Word),
 }
 
 #[derive(Debug)]
 pub enum RedirectType {
     Normal,
     Append,
+    Input,
+    /// `<>`
+    ReadWrite,
+    /// `>|`, overwrites the file even with noclobber set
+    Clobber,
 }
 
 #[derive(Debug)]
@@ -47,191 +73,1276 @@ pub struct Redirect {
     pub to: RedirectTo,
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum WordPart {
+    /// Unquoted text
+    Literal(String),
+    /// Text that came from quotes or a backslash escape
+    Quoted(String),
+    /// `$NAME`, `${NAME}` or a special parameter such as `$?`
+    Param { name: String, quoted: bool },
+    /// `$(commands)` or `` `commands` ``, replaced by what the commands write to stdout
+    Command { source: String, quoted: bool },
+    /// `$((expression))`, the expression is expanded like a double-quoted word first
+    Arith { expr: Word, quoted: bool },
+}
+
+/// A single shell word, kept unexpanded until the command it belongs to runs
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct Word(pub Vec<WordPart>);
+
+impl Word {
+    pub fn push_literal(&mut self, c: char) {
+        match self.0.last_mut() {
+            Some(WordPart::Literal(s)) => s.push(c),
+            _ => self.0.push(WordPart::Literal(c.into())),
+        }
+    }
+
+    fn push_quoted(&mut self, c: char) {
+        match self.0.last_mut() {
+            Some(WordPart::Quoted(s)) => s.push(c),
+            _ => self.0.push(WordPart::Quoted(c.into())),
+        }
+    }
+
+    fn push_str(&mut self, s: &str) {
+        s.chars().for_each(|c| self.push_literal(c));
+    }
+
+    /// Splits `NAME=value` into the name and the value, if the name is unquoted and valid
+    fn into_assignment(mut self) -> Result<(String, Word), Word> {
+        let Some(WordPart::Literal(first)) = self.0.first_mut() else {
+            return Err(self);
+        };
+        let Some((name, value)) = first.split_once('=') else {
+            return Err(self);
+        };
+        if !is_valid_name(name) {
+            return Err(self);
+        }
+
+        let name = name.to_owned();
+        *first = value.to_owned();
+        if first.is_empty() {
+            self.0.remove(0);
+        }
+        Ok((name, self))
+    }
+
+    /// true if expanding the word runs a command substitution
+    pub fn has_substitution(&self) -> bool {
+        self.0.iter().any(|part| match part {
+            WordPart::Command { .. } => true,
+            WordPart::Arith { expr, .. } => expr.has_substitution(),
+            _ => false,
+        })
+    }
+
+    /// the word as it was written, minus quotes
+    pub fn to_source(&self) -> String {
+        self.0
+            .iter()
+            .map(|part| match part {
+                WordPart::Literal(s) | WordPart::Quoted(s) => s.clone(),
+                WordPart::Param { name, .. } => format!("${name}"),
+                WordPart::Command { source, .. } => format!("$({source})"),
+                WordPart::Arith { expr, .. } => format!("$(({}))", expr.to_source()),
+            })
+            .collect()
+    }
+}
+
+/// How a pipeline is joined to the one before it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Connector {
+    /// `;`, or the start of the list
+    Seq,
+    /// `&&`
+    And,
+    /// `||`
+    Or,
+}
+
+/// `c_1 | c_2 | ... | c_n`
+#[derive(Default, Debug)]
+pub struct Pipeline {
+    pub commands: Vec<Command>,
+    /// ended with `&`, the shell doesn't wait for it to finish
+    pub background: bool,
+    /// the pipeline as it was written, for the job table
+    pub source: String,
+}
+
+pub type CommandList = Vec<(Connector, Pipeline)>;
+
 #[derive(Default, Debug)]
 pub struct Command {
-    pub name: String,
-    pub args: Vec<String>,
-    pub redirect: Option<Redirect>,
+    /// the `NAME=value` words before the command name
+    pub assignments: Vec<(String, Word)>,
+    /// command name followed by its arguments
+    pub words: Vec<Word>,
+    /// in the order they appear, which is the order they are applied in
+    pub redirects: Vec<Redirect>,
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum ParseError {
+    #[error("syntax error near unexpected token `{token}'")]
+    UnexpectedToken { offset: usize, token: String },
+    #[error("syntax error near end of input: {message}")]
+    UnexpectedEnd {
+        offset: usize,
+        message: &'static str,
+    },
+    /// more lines are needed to finish the here-document
+    #[error("here-document delimited by end of input (wanted `{delimiter}')")]
+    UnterminatedHereDoc { offset: usize, delimiter: String },
+    /// a `${...}` with something other than a parameter's name inside
+    #[error("{text}: bad substitution")]
+    BadSubstitution { offset: usize, text: String },
+}
+
+impl ParseError {
+    /// byte offset into the input where the error was found
+    pub fn offset(&self) -> usize {
+        match self {
+            Self::UnexpectedToken { offset, .. }
+            | Self::UnexpectedEnd { offset, .. }
+            | Self::UnterminatedHereDoc { offset, .. }
+            | Self::BadSubstitution { offset, .. } => *offset,
+        }
+    }
+
+    /// moves the error along by `by` bytes, for input that was parsed out of a larger one
+    fn shift(self, by: usize) -> Self {
+        self.map_offset(|offset| offset + by)
+    }
+
+    fn map_offset(mut self, f: impl FnOnce(usize) -> usize) -> Self {
+        match &mut self {
+            Self::UnexpectedToken { offset, .. }
+            | Self::UnexpectedEnd { offset, .. }
+            | Self::UnterminatedHereDoc { offset, .. }
+            | Self::BadSubstitution { offset, .. } => *offset = f(*offset),
+        }
+        self
+    }
+}
+
+/// Where the text of a copy made from the input came from, when the copy leaves some of
+/// it out, e.g. escaping backslashes. Each entry is an offset in the copy where a run of
+/// unchanged input starts, and the offset of that run in the input
+#[derive(Default)]
+struct OffsetMap(Vec<(usize, usize)>);
+
+impl OffsetMap {
+    fn push(&mut self, copy: usize, input: usize) {
+        self.0.push((copy, input));
+    }
+
+    /// The offset in the input of `offset` in the copy
+    fn to_input(&self, offset: usize) -> usize {
+        let i = self.0.partition_point(|(copy, _)| *copy <= offset);
+        match i.checked_sub(1).map(|i| self.0[i]) {
+            Some((copy, input)) => input + (offset - copy),
+            None => offset,
+        }
+    }
 }
 
 /// Follows single/double quote rules
 pub struct CommandParser<'a> {
+    input: &'a str,
     chars: Peekable<Chars<'a>>,
     buf: String,
+    /// where the here-document bodies read for the current line end
+    heredoc_end: Option<usize>,
+    /// where the last word of the command being parsed ends, before any comment
+    command_end: usize,
 }
 
 impl<'a> CommandParser<'a> {
     pub fn new(s: &'a str) -> Self {
         Self {
+            input: s,
             chars: s.chars().peekable(),
             buf: String::new(),
+            heredoc_end: None,
+            command_end: 0,
         }
     }
 
-    fn parse_single_quotes(&mut self) {
-        for c in self.chars.by_ref() {
-            if c == '\'' {
-                break;
+    /// byte offset of the next unconsumed character
+    fn offset(&self) -> usize {
+        self.input.len() - self.chars.clone().map(char::len_utf8).sum::<usize>()
+    }
+
+    fn unexpected_end(&self, message: &'static str) -> ParseError {
+        ParseError::UnexpectedEnd {
+            offset: self.offset(),
+            message,
+        }
+    }
+
+    /// error for the operator (or character) the parser is currently looking at
+    fn unexpected_token(&self) -> ParseError {
+        let mut chars = self.chars.clone();
+        let mut token: String = chars.next().into_iter().collect();
+        // a redirection at the very end is missing its target just like one before a newline
+        if token == "\n" || token.is_empty() {
+            token = "newline".into();
+        } else if token.chars().all(is_operator) {
+            token.extend(chars.take_while(|c| is_operator(*c) && *c != '\n'));
+        }
+        ParseError::UnexpectedToken {
+            offset: self.offset(),
+            token,
+        }
+    }
+
+    fn parse_single_quotes(&mut self, word: &mut Word) -> Result<(), ParseError> {
+        let parts = word.0.len();
+        loop {
+            match self.chars.next() {
+                Some('\'') => break,
+                Some(c) => word.push_quoted(c),
+                None => return Err(self.unexpected_end("unterminated single quote")),
             }
-            self.buf.push(c);
         }
+        // '' still has to produce an (empty) argument
+        if word.0.len() == parts {
+            word.0.push(WordPart::Quoted(String::new()));
+        }
+        Ok(())
     }
 
-    fn parse_double_quotes(&mut self) {
-        while let Some(c) = self.chars.next() {
+    fn parse_double_quotes(&mut self, word: &mut Word) -> Result<(), ParseError> {
+        let parts = word.0.len();
+        loop {
+            let Some(c) = self.chars.next() else {
+                return Err(self.unexpected_end("unterminated double quote"));
+            };
             match c {
                 '\"' => break,
-                '\\' => match self.chars.next().unwrap() {
-                    n @ ('\\' | '$' | '"') => {
-                        self.buf.push(n);
+                '\\' => match self.chars.next() {
+                    Some(n @ ('\\' | '$' | '"')) => {
+                        word.push_quoted(n);
                     }
-                    oth => {
-                        self.buf.push('\\');
-                        self.buf.push(oth)
+                    // an escaped newline joins the lines
+                    Some('\n') => {}
+                    Some(oth) => {
+                        word.push_quoted('\\');
+                        word.push_quoted(oth)
                     }
+                    None => return Err(self.unexpected_end("unterminated double quote")),
                 },
-                _ => self.buf.push(c),
+                '$' => self.parse_param(word, true)?,
+                '`' => self.parse_backticks(word, true)?,
+                _ => word.push_quoted(c),
             }
         }
+        if word.0.len() == parts {
+            word.0.push(WordPart::Quoted(String::new()));
+        }
+        Ok(())
+    }
+
+    /// Called after a `$` has been consumed
+    fn parse_param(&mut self, word: &mut Word, quoted: bool) -> Result<(), ParseError> {
+        let name = match self.chars.peek() {
+            Some('(') => {
+                self.chars.next(); // (
+                if self.chars.next_if_eq(&'(').is_some() {
+                    let expr = self.parse_arith()?;
+                    word.0.push(WordPart::Arith { expr, quoted });
+                    return Ok(());
+                }
+                return self.parse_command_substitution(word, quoted);
+            }
+            Some('{') => {
+                // the `$` before it
+                let offset = self.offset() - 1;
+                self.chars.next(); // {
+                let mut name = String::new();
+                loop {
+                    match self.chars.next() {
+                        Some('}') => break,
+                        Some(c) => name.push(c),
+                        None => return Err(self.unexpected_end("expected '}'")),
+                    }
+                }
+                let special = matches!(name.as_str(), "?" | "$" | "!" | "#" | "@" | "*");
+                let positional = !name.is_empty() && name.chars().all(|c| c.is_ascii_digit());
+                if !(is_valid_name(&name) || special || positional) {
+                    return Err(ParseError::BadSubstitution {
+                        offset,
+                        text: format!("${{{name}}}"),
+                    });
+                }
+                name
+            }
+            Some(&c @ ('?' | '$' | '!' | '#' | '@' | '*' | '0'..='9')) => {
+                self.chars.next();
+                c.into()
+            }
+            Some(c) if c.is_ascii_alphabetic() || *c == '_' => {
+                let mut name = String::new();
+                while let Some(c) = self
+                    .chars
+                    .next_if(|c| c.is_ascii_alphanumeric() || *c == '_')
+                {
+                    name.push(c);
+                }
+                name
+            }
+            // not a parameter, a lone '$' is taken literally
+            _ => {
+                if quoted {
+                    word.push_quoted('$');
+                } else {
+                    word.push_literal('$');
+                }
+                return Ok(());
+            }
+        };
+        word.0.push(WordPart::Param { name, quoted });
+        Ok(())
     }
 
-    fn parse_string(&mut self) {
-        if self.chars.peek().is_none() && self.buf.is_empty() {
-            panic!("Expected string, found end of input")
+    /// Called after `$(`, reads the commands up to the matching `)`. Quoted parentheses
+    /// don't count towards the matching
+    fn parse_command_substitution(
+        &mut self,
+        word: &mut Word,
+        quoted: bool,
+    ) -> Result<(), ParseError> {
+        let start = self.offset();
+        let unterminated = |parser: &Self| parser.unexpected_end("expected ')'");
+        let mut depth = 0;
+        loop {
+            match self.chars.next() {
+                Some(')') if depth == 0 => break,
+                Some(')') => depth -= 1,
+                Some('(') => depth += 1,
+                Some('\\') => {
+                    self.chars.next();
+                }
+                Some(q @ ('\'' | '"')) => loop {
+                    match self.chars.next() {
+                        Some(c) if c == q => break,
+                        Some('\\') if q == '"' => {
+                            self.chars.next();
+                        }
+                        Some(_) => {}
+                        None => return Err(unterminated(self)),
+                    }
+                },
+                Some(_) => {}
+                None => return Err(unterminated(self)),
+            }
         }
+        let source = &self.input[start..self.offset() - 1];
 
-        while let Some(c) = self.chars.next() {
+        // syntax errors inside are reported now rather than when the word is expanded
+        CommandParser::new(source)
+            .parse()
+            .map_err(|e| e.shift(start))?;
+        word.0.push(WordPart::Command {
+            source: source.to_owned(),
+            quoted,
+        });
+        Ok(())
+    }
+
+    /// Called after `((`, reads an arithmetic expression up to the matching `))`
+    fn parse_arith(&mut self) -> Result<Word, ParseError> {
+        let start = self.offset();
+        let mut depth = 0;
+        loop {
+            match self.chars.next() {
+                Some(')') if depth == 0 => {
+                    if self.chars.next_if_eq(&')').is_some() {
+                        break;
+                    }
+                    return Err(match self.chars.peek() {
+                        None => self.unexpected_end("expected '))'"),
+                        Some(_) => self.unexpected_token(),
+                    });
+                }
+                Some(')') => depth -= 1,
+                Some('(') => depth += 1,
+                Some(_) => {}
+                None => return Err(self.unexpected_end("expected '))'")),
+            }
+        }
+        let expr = &self.input[start..self.offset() - 2];
+
+        // parameters and command substitutions in it expand as they would in double quotes
+        CommandParser::new(expr)
+            .parse_heredoc_body()
+            .map_err(|e| e.shift(start))
+    }
+
+    /// Called after an opening backtick, reads the commands up to the closing one.
+    /// A backslash only escapes `$`, `` ` `` and another backslash
+    fn parse_backticks(&mut self, word: &mut Word, quoted: bool) -> Result<(), ParseError> {
+        let mut source = String::new();
+        // errors in `source` are reported where they are in the input, around the escapes
+        let mut offsets = OffsetMap::default();
+        offsets.push(0, self.offset());
+        loop {
+            match self.chars.next() {
+                Some('`') => break,
+                Some('\\') => match self.chars.next() {
+                    Some(c @ ('$' | '`' | '\\')) => {
+                        offsets.push(source.len(), self.offset() - c.len_utf8());
+                        source.push(c);
+                        offsets.push(source.len(), self.offset());
+                    }
+                    Some(c) => {
+                        source.push('\\');
+                        source.push(c);
+                    }
+                    None => return Err(self.unexpected_end("unterminated backquote")),
+                },
+                Some(c) => source.push(c),
+                None => return Err(self.unexpected_end("unterminated backquote")),
+            }
+        }
+
+        CommandParser::new(&source)
+            .parse()
+            .map_err(|e| e.map_offset(|offset| offsets.to_input(offset)))?;
+        word.0.push(WordPart::Command { source, quoted });
+        Ok(())
+    }
+
+    fn parse_string(&mut self) -> Result<Word, ParseError> {
+        let mut word = Word::default();
+        word.push_str(&self.buf);
+        self.buf.clear();
+
+        match self.chars.peek() {
+            None if word.0.is_empty() => return Err(self.unexpected_token()),
+            Some(&c) if is_operator(c) && word.0.is_empty() => return Err(self.unexpected_token()),
+            _ => {}
+        }
+
+        while let Some(c) = self.chars.next_if(|c| !is_operator(*c)) {
             if c.is_ascii_whitespace() {
                 break;
             }
 
             match c {
-                '>' => {
-                    panic!("Expected a string, but found a redirection")
-                }
-                '|' => {
-                    panic!("Expected a string, but found a pipe")
-                }
-                '\\' => self.buf.push(self.chars.next().unwrap()),
-                '\'' => self.parse_single_quotes(),
-                '"' => self.parse_double_quotes(),
-                _ => self.buf.push(c),
+                '\\' => match self.chars.next() {
+                    // an escaped newline joins the lines
+                    Some('\n') => {}
+                    Some(c) => word.push_quoted(c),
+                    None => return Err(self.unexpected_end("expected a character after '\\'")),
+                },
+                '\'' => self.parse_single_quotes(&mut word)?,
+                '"' => self.parse_double_quotes(&mut word)?,
+                '$' => self.parse_param(&mut word, false)?,
+                '`' => self.parse_backticks(&mut word, false)?,
+                _ => word.push_literal(c),
             }
         }
+        Ok(word)
+    }
+
+    fn parse_fd(&mut self) -> Result<Fd, ParseError> {
+        let offset = self.offset() - self.buf.len();
+        let fd = Fd::from_str(&self.buf).map_err(|_| ParseError::UnexpectedToken {
+            offset,
+            token: self.buf.clone(),
+        });
+        self.buf.clear();
+        fd
     }
 
-    fn try_parse_redirect(&mut self) -> Option<Redirect> {
+    fn try_parse_redirect(&mut self) -> Result<Option<Redirect>, ParseError> {
         while let Some(c) = self.chars.next_if(|c| c.is_ascii_digit()) {
             self.buf.push(c);
         }
 
         match self.chars.peek() {
             Some('>') => {
+                let from = self.parse_fd()?;
                 self.chars.next(); // >
-                let from = Fd::from_str(&self.buf).expect("Expected a valid file descriptor");
-                self.buf.clear();
 
                 let r_type = match self.chars.peek() {
                     Some('>') => {
                         self.chars.next(); // >
                         RedirectType::Append
                     }
+                    Some('|') => {
+                        self.chars.next(); // |
+                        RedirectType::Clobber
+                    }
                     _ => RedirectType::Normal,
                 };
 
                 let to = match self.chars.peek() {
-                    Some('&') => {
-                        self.chars.next(); // &
-                        self.parse_string();
-                        RedirectTo::Fd(
-                            Fd::from_str(&self.buf).expect("Expected a valid file descriptor"),
-                        )
+                    Some('&') => self.parse_dup_target()?,
+                    _ => {
+                        self.advance();
+                        RedirectTo::File(self.parse_string()?)
+                    }
+                };
+
+                Ok(Some(Redirect { r_type, from, to }))
+            }
+            Some('<') => {
+                let from = if self.buf.is_empty() {
+                    Fd::Stdin
+                } else {
+                    self.parse_fd()?
+                };
+                self.chars.next(); // <
+
+                let mut r_type = RedirectType::Input;
+                let to = match self.chars.peek() {
+                    Some('&') => self.parse_dup_target()?,
+                    Some('>') => {
+                        self.chars.next(); // >
+                        self.advance();
+                        r_type = RedirectType::ReadWrite;
+                        RedirectTo::File(self.parse_string()?)
+                    }
+                    Some('<') => {
+                        self.chars.next(); // <
+                        if self.chars.next_if_eq(&'<').is_some() {
+                            self.advance();
+                            let mut word = self.parse_string()?;
+                            word.0.push(WordPart::Quoted("\n".into()));
+                            RedirectTo::HereDoc(word)
+                        } else {
+                            let strip_tabs = self.chars.next_if_eq(&'-').is_some();
+                            self.advance();
+                            let delimiter = self.parse_string()?;
+                            RedirectTo::HereDoc(self.read_heredoc(delimiter, strip_tabs)?)
+                        }
                     }
                     _ => {
                         self.advance();
-                        self.parse_string();
-                        RedirectTo::File(self.buf.clone())
+                        RedirectTo::File(self.parse_string()?)
                     }
                 };
 
-                self.buf.clear();
-                Some(Redirect { r_type, from, to })
+                Ok(Some(Redirect { r_type, from, to }))
             }
             _ => {
                 // fallback
-                None
+                Ok(None)
             }
         }
     }
 
-    pub fn parse_command(&mut self) -> Option<Command> {
-        if self.advance() {
-            return None; // empty string
+    /// parses `&> file` and `&>> file`, which are shorthand for `> file 2>&1` and `>> file 2>&1`
+    fn parse_both_redirect(&mut self) -> Result<[Redirect; 2], ParseError> {
+        self.chars.next(); // &
+        let stdout = self
+            .try_parse_redirect()?
+            .expect("a '>' follows, so this is a redirection");
+        let stderr = Redirect {
+            r_type: RedirectType::Normal,
+            from: Fd::Stderr,
+            to: RedirectTo::Fd(Fd::Stdout),
+        };
+        Ok([stdout, stderr])
+    }
+
+    /// parses the `&M` or `&-` of `N>&M`, `N<&M` and `N>&-`
+    fn parse_dup_target(&mut self) -> Result<RedirectTo, ParseError> {
+        self.chars.next(); // &
+        if self.chars.next_if_eq(&'-').is_some() {
+            return Ok(RedirectTo::Close);
+        }
+        while let Some(c) = self.chars.next_if(|c| c.is_ascii_digit()) {
+            self.buf.push(c);
+        }
+        if self.buf.is_empty() {
+            return Err(self.unexpected_token());
         }
+        Ok(RedirectTo::Fd(self.parse_fd()?))
+    }
 
-        let mut comm = Command {
-            name: {
-                self.parse_string();
-                self.buf.drain(..).collect()
-            },
-            ..Default::default()
+    /// Reads the body of a here-document. Bodies start on the line after the one
+    /// being parsed, one after the other when a line has several of them.
+    fn read_heredoc(&mut self, delimiter: Word, strip_tabs: bool) -> Result<Word, ParseError> {
+        // quoting any part of the delimiter turns off expansion in the body
+        let expand = delimiter
+            .0
+            .iter()
+            .all(|part| matches!(part, WordPart::Literal(_)));
+        let delimiter = delimiter.to_source();
+
+        let unterminated = || ParseError::UnterminatedHereDoc {
+            offset: self.input.len(),
+            delimiter: delimiter.clone(),
+        };
+
+        let mut pos = match self.heredoc_end {
+            Some(end) => end,
+            None => {
+                let offset = self.offset();
+                match self.input[offset..].find('\n') {
+                    Some(i) => offset + i + 1,
+                    None => return Err(unterminated()),
+                }
+            }
         };
 
+        let mut body = String::new();
+        // where each line of the body starts in the input, for errors in it
+        let mut offsets = OffsetMap::default();
         loop {
-            if self.advance() {
+            if pos >= self.input.len() {
+                return Err(unterminated());
+            }
+            let rest = &self.input[pos..];
+            let (line, next) = match rest.find('\n') {
+                Some(i) => (&rest[..i], pos + i + 1),
+                None => (rest, self.input.len()),
+            };
+
+            // the tabs `<<-` strips come before where the line starts
+            let (line, start) = if strip_tabs {
+                let stripped = line.trim_start_matches('\t');
+                (stripped, pos + line.len() - stripped.len())
+            } else {
+                (line, pos)
+            };
+            pos = next;
+            if line == delimiter {
                 break;
             }
+            offsets.push(body.len(), start);
+            body.push_str(line);
+            body.push('\n');
+        }
+        self.heredoc_end = Some(pos);
 
-            if self.chars.peek() == Some(&'|') {
+        if expand {
+            CommandParser::new(&body)
+                .parse_heredoc_body()
+                .map_err(|e| e.map_offset(|offset| offsets.to_input(offset)))
+        } else {
+            Ok(Word(vec![WordPart::Quoted(body)]))
+        }
+    }
+
+    /// Like the inside of double quotes, but `"` has no special meaning
+    fn parse_heredoc_body(&mut self) -> Result<Word, ParseError> {
+        let mut word = Word(vec![WordPart::Quoted(String::new())]);
+        while let Some(c) = self.chars.next() {
+            match c {
+                '\\' => match self.chars.next() {
+                    Some(n @ ('\\' | '$' | '`')) => word.push_quoted(n),
+                    // escaped newlines join lines
+                    Some('\n') => {}
+                    Some(oth) => {
+                        word.push_quoted('\\');
+                        word.push_quoted(oth)
+                    }
+                    None => word.push_quoted('\\'),
+                },
+                '$' => self.parse_param(&mut word, true)?,
+                '`' => self.parse_backticks(&mut word, true)?,
+                _ => word.push_quoted(c),
+            }
+        }
+        Ok(word)
+    }
+
+    pub fn parse_command(&mut self) -> Result<Option<Command>, ParseError> {
+        let mut comm = Command::default();
+        // `(( expression ))`, which only redirections can follow
+        let mut arith = false;
+
+        loop {
+            self.command_end = self.offset();
+            if self.advance() {
+                break;
+            }
+            if comm.words.is_empty() && comm.assignments.is_empty() && self.peek_op("((") {
+                self.chars.nth(1); // ((
+                let expr = self.parse_arith()?;
+                comm.words = vec![Word(vec![WordPart::Literal("((".into())]), expr];
+                arith = true;
+                continue;
+            }
+            if self.peek_op("&>") {
+                comm.redirects.extend(self.parse_both_redirect()?);
+                continue;
+            }
+            if self.chars.peek().is_some_and(|c| is_separator(*c)) {
                 break;
             }
 
             // check if redirection
-            match self.try_parse_redirect() {
-                Some(r) => comm.redirect = Some(r),
+            match self.try_parse_redirect()? {
+                Some(r) => comm.redirects.push(r),
+                None if arith => return Err(self.unexpected_token()),
                 None => {
-                    self.parse_string();
-                    comm.args.push(self.buf.drain(..).collect())
+                    let word = self.parse_string()?;
+                    // assignments only come before the command name
+                    if !comm.words.is_empty() {
+                        comm.words.push(word);
+                        continue;
+                    }
+                    match word.into_assignment() {
+                        Ok(assignment) => comm.assignments.push(assignment),
+                        Err(word) => comm.words.push(word),
+                    }
                 }
             }
         }
 
-        Some(comm)
+        if comm.words.is_empty() && comm.redirects.is_empty() && comm.assignments.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(comm))
     }
 
     /// returns a pipeline of commands, i.e. [c_1, c_2, ..., c_n] models 'c_1 | c_2 | ... | c_n'
-    pub fn parse(mut self) -> Vec<Command> {
-        let command = match self.parse_command() {
-            None => return vec![],
+    fn parse_pipeline(&mut self) -> Result<Pipeline, ParseError> {
+        let start = self.offset();
+        let command = match self.parse_command()? {
+            None => return Ok(Pipeline::default()),
             Some(c) => c,
         };
         let mut pipeline = vec![command];
 
+        while self.chars.peek() == Some(&'|') && !self.peek_op("||") {
+            self.chars.next(); // |
+            self.skip_linebreaks();
+            match self.parse_command()? {
+                Some(c) => pipeline.push(c),
+                None if self.chars.peek().is_none() => {
+                    return Err(self.unexpected_end("expected a command after '|'"))
+                }
+                None => return Err(self.unexpected_token()),
+            }
+        }
+
+        let source = self.input[start..self.command_end].trim().to_owned();
+        // a lone '&', not the start of '&&' or '&>'
+        let background = !self.peek_op("&&") && self.chars.next_if_eq(&'&').is_some();
+        Ok(Pipeline {
+            commands: pipeline,
+            background,
+            source,
+        })
+    }
+
+    /// returns the pipelines of a command list, i.e. 'p_1 && p_2; p_3' becomes
+    /// [(Seq, p_1), (And, p_2), (Seq, p_3)]
+    ///
+    /// `&` only sends the pipeline before it to the background, so in 'p_1 && p_2 &' p_1 is
+    /// still waited for
+    pub fn parse(mut self) -> Result<CommandList, ParseError> {
+        let mut list = vec![];
+        let mut connector = Connector::Seq;
+
         loop {
-            match self.chars.next() {
-                Some('|') => {
-                    pipeline.push(
-                        self.parse_command()
-                            .expect("Expected a command to pipeline, but found end of input"),
-                    );
+            let pipeline = self.parse_pipeline()?;
+            if pipeline.commands.is_empty() {
+                match self.chars.peek() {
+                    None if connector == Connector::Seq => break,
+                    None => {
+                        return Err(self.unexpected_end("expected a command after '&&' or '||'"))
+                    }
+                    // blank line
+                    Some('\n') if connector == Connector::Seq => {
+                        self.next_line();
+                        continue;
+                    }
+                    Some(_) => return Err(self.unexpected_token()),
+                }
+            }
+            let background = pipeline.background;
+            list.push((connector, pipeline));
+
+            // '&' has already been consumed and separates like ';'
+            if background {
+                connector = Connector::Seq;
+                if self.chars.peek() == Some(&'\n') {
+                    self.next_line();
                 }
-                None => break,
-                _ => unreachable!(),
+                if self.advance() {
+                    break;
+                }
+                continue;
+            }
+
+            connector = if self.peek_op("&&") {
+                Connector::And
+            } else if self.peek_op("||") {
+                Connector::Or
+            } else {
+                match self.chars.peek() {
+                    Some(';' | '\n') => Connector::Seq,
+                    None => break,
+                    Some(_) => return Err(self.unexpected_token()),
+                }
+            };
+            if self.chars.peek() == Some(&'\n') {
+                self.next_line();
+            } else if connector == Connector::Seq {
+                self.chars.next(); // ;
+            } else {
+                self.chars.nth(1); // && or ||
+                self.skip_linebreaks();
+            }
+
+            // a trailing ';' is allowed
+            if connector == Connector::Seq && self.advance() {
+                break;
             }
         }
-        pipeline
+        Ok(list)
+    }
+
+    /// true if the remaining input starts with `op`
+    fn peek_op(&self, op: &str) -> bool {
+        let mut chars = self.chars.clone();
+        op.chars().all(|c| chars.next() == Some(c))
     }
 
     // true if exhausted iterator
     fn advance(&mut self) -> bool {
-        while self.chars.next_if(|c| c.is_ascii_whitespace()).is_some() {}
+        while self.chars.next_if(|c| *c == ' ' || *c == '\t').is_some() {}
+        // an unquoted '#' at the start of a word comments out the rest of the line
+        if self.chars.peek() == Some(&'#') {
+            while self.chars.next_if(|c| *c != '\n').is_some() {}
+        }
 
         self.chars.peek().is_none()
     }
+
+    /// Consumes a newline, skipping over any here-document bodies that follow it
+    fn next_line(&mut self) {
+        match self.heredoc_end.take() {
+            Some(end) => self.chars = self.input[end..].chars().peekable(),
+            None => {
+                self.chars.next();
+            }
+        }
+    }
+
+    /// newlines are allowed after operators that need something to follow them
+    fn skip_linebreaks(&mut self) {
+        while !self.advance() && self.chars.peek() == Some(&'\n') {
+            self.next_line();
+        }
+    }
+}
+
+/// characters that end a word even when not preceded by whitespace
+fn is_operator(c: char) -> bool {
+    matches!(c, '|' | '&' | ';' | '>' | '<' | '\n')
+}
+
+/// characters that end a command
+fn is_separator(c: char) -> bool {
+    matches!(c, '|' | '&' | ';' | '\n')
 }
 
 #[test]
 fn test() {
     let parser = CommandParser::new("echo hello testing 2>&3 still an arg");
-    dbg!(parser.parse());
+    let list = parser.parse().unwrap();
+    let comm = &list[0].1.commands[0];
+    let words: Vec<_> = comm.words.iter().map(Word::to_source).collect();
+    assert_eq!(words, ["echo", "hello", "testing", "still", "an", "arg"]);
+    assert!(matches!(
+        comm.redirects[..],
+        [Redirect {
+            from: Fd::Stderr,
+            to: RedirectTo::Fd(Fd::Other(3)),
+            ..
+        }]
+    ));
+}
+
+#[test]
+fn test_params() {
+    let list = CommandParser::new(r#"echo $HOME "${A}b $?" '$B' \$C $"#)
+        .parse()
+        .unwrap();
+    let words = &list[0].1.commands[0].words;
+    assert_eq!(
+        words[1],
+        Word(vec![WordPart::Param {
+            name: "HOME".into(),
+            quoted: false
+        }])
+    );
+    assert_eq!(
+        words[2],
+        Word(vec![
+            WordPart::Param {
+                name: "A".into(),
+                quoted: true
+            },
+            WordPart::Quoted("b ".into()),
+            WordPart::Param {
+                name: "?".into(),
+                quoted: true
+            },
+        ])
+    );
+    assert_eq!(words[3], Word(vec![WordPart::Quoted("$B".into())]));
+    assert_eq!(
+        words[4],
+        Word(vec![
+            WordPart::Quoted("$".into()),
+            WordPart::Literal("C".into())
+        ])
+    );
+    assert_eq!(words[5], Word(vec![WordPart::Literal("$".into())]));
+
+    // positional parameters only take a single digit without braces
+    let list = CommandParser::new(r#"echo $12 "$@" $# ${10}"#)
+        .parse()
+        .unwrap();
+    let names: Vec<_> = list[0].1.commands[0].words[1..]
+        .iter()
+        .map(|w| match &w.0[0] {
+            WordPart::Param { name, .. } => name.as_str(),
+            _ => unreachable!(),
+        })
+        .collect();
+    assert_eq!(names, ["1", "@", "#", "10"]);
+}
+
+#[test]
+fn test_list() {
+    let list = CommandParser::new("a | b && c;d||e|f ;").parse().unwrap();
+    let shape: Vec<_> = list.iter().map(|(c, p)| (*c, p.commands.len())).collect();
+    assert_eq!(
+        shape,
+        [
+            (Connector::Seq, 2),
+            (Connector::And, 1),
+            (Connector::Seq, 1),
+            (Connector::Or, 2)
+        ]
+    );
+}
+
+#[test]
+fn test_comments() {
+    let list = CommandParser::new("#!/bin/sh\necho a#b '#c' # d; e\n  # f\ng && # h\n i #")
+        .parse()
+        .unwrap();
+    let sources: Vec<_> = list.iter().map(|(_, p)| p.source.as_str()).collect();
+    assert_eq!(sources, ["echo a#b '#c'", "g", "i"]);
+    let words = &list[0].1.commands[0].words;
+    assert_eq!(words.len(), 3);
+    assert_eq!(words[1], Word(vec![WordPart::Literal("a#b".into())]));
+    assert_eq!(list[2].0, Connector::And);
+
+    assert!(CommandParser::new("# only a comment")
+        .parse()
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn test_errors() {
+    let token = |offset, token: &str| ParseError::UnexpectedToken {
+        offset,
+        token: token.into(),
+    };
+    let parse_err = |s| CommandParser::new(s).parse().unwrap_err();
+
+    assert_eq!(parse_err("echo > | cat"), token(7, "|"));
+    assert_eq!(parse_err("| cat"), token(0, "|"));
+    assert_eq!(parse_err("a;; b"), token(2, ";"));
+    assert_eq!(parse_err("echo hi |").offset(), 9);
+    assert_eq!(parse_err("echo >"), token(6, "newline"));
+    assert_eq!(parse_err("echo a &&").offset(), 9);
+    assert_eq!(parse_err("echo \\").offset(), 6);
+    assert_eq!(parse_err("echo 'abc").offset(), 9);
+    assert_eq!(parse_err("echo ${A").offset(), 8);
+    assert_eq!(parse_err("a & ; b"), token(4, ";"));
+}
+
+#[test]
+fn test_redirects() {
+    let list = CommandParser::new("> out cmd 2> err arg >> out2")
+        .parse()
+        .unwrap();
+    let comm = &list[0].1.commands[0];
+    assert_eq!(comm.words.len(), 2);
+    let fds: Vec<_> = comm.redirects.iter().map(|r| r.from.raw()).collect();
+    assert_eq!(fds, [1, 2, 1]);
+    assert!(matches!(comm.redirects[2].r_type, RedirectType::Append));
+}
+
+#[test]
+fn test_dup_redirects() {
+    let list = CommandParser::new("cmd 2>&1 >&2 3>&- 4>&5")
+        .parse()
+        .unwrap();
+    let redirects: Vec<_> = list[0].1.commands[0]
+        .redirects
+        .iter()
+        .map(|r| match &r.to {
+            RedirectTo::Fd(to) => (r.from.raw(), Some(to.raw())),
+            RedirectTo::Close => (r.from.raw(), None),
+            _ => unreachable!(),
+        })
+        .collect();
+    assert_eq!(
+        redirects,
+        [(2, Some(1)), (1, Some(2)), (3, None), (4, Some(5))]
+    );
+}
+
+#[test]
+fn test_heredocs() {
+    let input = "cat <<A <<-'B' && cat <<< \"$x\"\na $y\nA\n\t\t$y\n\tB\necho done";
+    let list = CommandParser::new(input).parse().unwrap();
+    assert_eq!(list.len(), 3);
+
+    let bodies: Vec<_> = list[0].1.commands[0]
+        .redirects
+        .iter()
+        .map(|r| match &r.to {
+            RedirectTo::HereDoc(word) => word,
+            _ => unreachable!(),
+        })
+        .collect();
+    assert_eq!(
+        bodies[0].0[1],
+        WordPart::Param {
+            name: "y".into(),
+            quoted: true
+        }
+    );
+    assert_eq!(*bodies[1], Word(vec![WordPart::Quoted("$y\n".into())]));
+    assert_eq!(
+        list[2].1.commands[0].words[1],
+        Word(vec![WordPart::Literal("done".into())])
+    );
+
+    assert!(matches!(
+        CommandParser::new("cat <<EOF\nbody").parse(),
+        Err(ParseError::UnterminatedHereDoc { .. })
+    ));
+
+    // errors in a body are where they are in the input, after any tabs `<<-` strips
+    let parse_err = |s| CommandParser::new(s).parse().unwrap_err();
+    assert_eq!(parse_err("cat <<EOF\nbody\n${x.}\nEOF").offset(), 15);
+    assert_eq!(parse_err("cat <<-EOF\n\t\t${x.}\nEOF").offset(), 13);
+    assert_eq!(parse_err("é <<EOF\na${x.}\nEOF").offset(), 10);
+}
+
+#[test]
+fn test_numbered_fds() {
+    let list = CommandParser::new("cmd 3> a 4< b 5<> c <> d >| e")
+        .parse()
+        .unwrap();
+    let redirects: Vec<_> = list[0].1.commands[0]
+        .redirects
+        .iter()
+        .map(|r| (r.from.raw(), &r.r_type))
+        .collect();
+    assert!(matches!(
+        redirects[..],
+        [
+            (3, RedirectType::Normal),
+            (4, RedirectType::Input),
+            (5, RedirectType::ReadWrite),
+            (0, RedirectType::ReadWrite),
+            (1, RedirectType::Clobber)
+        ]
+    ));
+}
+
+#[test]
+fn test_both_redirects() {
+    let list = CommandParser::new("cmd &> a; cmd&>>b").parse().unwrap();
+    for (_, pipeline) in &list {
+        let redirects = &pipeline.commands[0].redirects;
+        assert!(matches!(
+            redirects[..],
+            [
+                Redirect {
+                    from: Fd::Stdout,
+                    to: RedirectTo::File(_),
+                    ..
+                },
+                Redirect {
+                    from: Fd::Stderr,
+                    to: RedirectTo::Fd(Fd::Stdout),
+                    ..
+                }
+            ]
+        ));
+    }
+    assert!(matches!(
+        list[0].1.commands[0].redirects[0].r_type,
+        RedirectType::Normal
+    ));
+    assert!(matches!(
+        list[1].1.commands[0].redirects[0].r_type,
+        RedirectType::Append
+    ));
+    assert_eq!(list[1].1.commands[0].words.len(), 1);
+}
+
+#[test]
+fn test_background() {
+    let list = CommandParser::new("sleep 1 | cat & echo a&&echo b &\n  c &")
+        .parse()
+        .unwrap();
+    let shape: Vec<_> = list
+        .iter()
+        .map(|(c, p)| (*c, p.background, p.source.as_str()))
+        .collect();
+    assert_eq!(
+        shape,
+        [
+            (Connector::Seq, true, "sleep 1 | cat"),
+            (Connector::Seq, false, "echo a"),
+            (Connector::And, true, "echo b"),
+            (Connector::Seq, true, "c")
+        ]
+    );
+}
+
+#[test]
+fn test_assignments() {
+    let list = CommandParser::new(r#"A=1 B="x y" >out cmd C=2; D= "E"=3; F=$A"#)
+        .parse()
+        .unwrap();
+    let comm = &list[0].1.commands[0];
+    let names: Vec<_> = comm.assignments.iter().map(|(n, _)| n.as_str()).collect();
+    assert_eq!(names, ["A", "B"]);
+    assert_eq!(
+        comm.assignments[1].1,
+        Word(vec![WordPart::Quoted("x y".into())])
+    );
+    assert_eq!(comm.words.len(), 2);
+
+    let comm = &list[1].1.commands[0];
+    assert_eq!(comm.assignments[0], ("D".into(), Word::default()));
+    assert_eq!(comm.words.len(), 1);
+
+    let comm = &list[2].1.commands[0];
+    assert!(comm.words.is_empty());
+    assert!(matches!(
+        comm.assignments[0].1 .0[..],
+        [WordPart::Param { .. }]
+    ));
+}
+
+#[test]
+fn test_command_substitution() {
+    let list = CommandParser::new(r#"echo $(echo ")"; (x)) "a$(b)" `c \`d\` $e` x$"#)
+        .parse()
+        .unwrap();
+    let words = &list[0].1.commands[0].words;
+    assert_eq!(
+        words[1],
+        Word(vec![WordPart::Command {
+            source: r#"echo ")"; (x)"#.into(),
+            quoted: false
+        }])
+    );
+    assert_eq!(
+        words[2],
+        Word(vec![
+            WordPart::Quoted("a".into()),
+            WordPart::Command {
+                source: "b".into(),
+                quoted: true
+            }
+        ])
+    );
+    assert_eq!(
+        words[3],
+        Word(vec![WordPart::Command {
+            source: "c `d` $e".into(),
+            quoted: false
+        }])
+    );
+    assert_eq!(words[4], Word(vec![WordPart::Literal("x$".into())]));
+
+    assert!(CommandParser::new("echo $(echo").parse().is_err());
+    assert!(CommandParser::new("echo `echo").parse().is_err());
+    assert_eq!(
+        CommandParser::new("echo $(a |)").parse().unwrap_err(),
+        ParseError::UnexpectedEnd {
+            offset: 10,
+            message: "expected a command after '|'"
+        }
+    );
+    // offsets inside backticks count the escaping backslashes
+    assert_eq!(
+        CommandParser::new("echo `\\$é${x.}`")
+            .parse()
+            .unwrap_err()
+            .offset(),
+        10
+    );
+    assert_eq!(
+        CommandParser::new("echo `a |`")
+            .parse()
+            .unwrap_err()
+            .offset(),
+        9
+    );
+}
+
+#[test]
+fn test_arith() {
+    let list = CommandParser::new("echo $((1 + (2 * $x))) \"$(( y ))\"; (( a = 1 )) >out")
+        .parse()
+        .unwrap();
+    let words = &list[0].1.commands[0].words;
+    let WordPart::Arith {
+        expr,
+        quoted: false,
+    } = &words[1].0[0]
+    else {
+        panic!("expected an arithmetic expansion, got {:?}", words[1]);
+    };
+    assert_eq!(expr.to_source(), "1 + (2 * $x)");
+    assert!(matches!(
+        words[2].0[0],
+        WordPart::Arith { quoted: true, .. }
+    ));
+
+    let comm = &list[1].1.commands[0];
+    assert_eq!(comm.words[0], Word(vec![WordPart::Literal("((".into())]));
+    assert_eq!(comm.words[1].to_source(), " a = 1 ");
+    assert_eq!(comm.redirects.len(), 1);
+
+    assert!(CommandParser::new("echo $((1 + 2)").parse().is_err());
+    assert!(CommandParser::new("(( 1 )) 2").parse().is_err());
+}
+
+#[test]
+fn test_bad_substitution() {
+    assert!(CommandParser::new("echo ${HOME} ${10} ${#} ${_x1}")
+        .parse()
+        .is_ok());
+    assert_eq!(
+        CommandParser::new("echo ${HOME:-x}").parse().unwrap_err(),
+        ParseError::BadSubstitution {
+            offset: 5,
+            text: "${HOME:-x}".into()
+        }
+    );
+    assert!(matches!(
+        CommandParser::new("echo \"${}\"").parse(),
+        Err(ParseError::BadSubstitution { offset: 6, .. })
+    ));
+    assert!(CommandParser::new("echo ${1a}").parse().is_err());
 }
@@ -0,0 +1,213 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, stderr, stdin, stdout, Read, Write},
+    os::fd::{AsFd, OwnedFd},
+    thread,
+};
+
+use thiserror::Error;
+
+use super::{
+    expand::{self, ExpandError},
+    parser::{Redirect, RedirectTo, RedirectType, Word},
+};
+use crate::{shell::Shell, variables::Options};
+
+/// A redirection that could not be set up, the command it belongs to is not run
+#[derive(Debug, Error)]
+#[error("{target}: {}", describe(.source))]
+pub struct RedirectError {
+    /// the file name or fd being redirected to
+    target: String,
+    source: io::Error,
+}
+
+impl RedirectError {
+    /// `word` expanded to no file name or to more than one
+    pub fn ambiguous(word: &Word) -> Self {
+        RedirectError {
+            target: word.to_source(),
+            source: io::Error::other("ambiguous redirect"),
+        }
+    }
+}
+
+/// The message of an io error without the "(os error N)" suffix, like strerror
+pub fn describe(e: &io::Error) -> String {
+    let message = e.to_string();
+    match message.find(" (os error") {
+        Some(i) => message[..i].to_owned(),
+        None => message,
+    }
+}
+
+fn new_file(
+    r_type: &RedirectType,
+    file_name: String,
+    options: &Options,
+) -> Result<File, RedirectError> {
+    let file = match r_type {
+        RedirectType::Input => File::open(&file_name),
+        RedirectType::Normal if options.noclobber => open_noclobber(&file_name),
+        _ => File::options()
+            .read(matches!(r_type, RedirectType::ReadWrite))
+            .append(matches!(r_type, RedirectType::Append))
+            .truncate(matches!(
+                r_type,
+                RedirectType::Normal | RedirectType::Clobber
+            ))
+            .write(true)
+            .create(true)
+            .open(&file_name),
+    };
+    file.map_err(|source| RedirectError {
+        target: file_name,
+        source,
+    })
+}
+
+/// Opens a file for `>` without truncating an existing regular file, things like
+/// `/dev/null` can still be written to
+fn open_noclobber(file_name: &str) -> io::Result<File> {
+    match File::options().write(true).create_new(true).open(file_name) {
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            if fs::metadata(file_name)?.is_file() {
+                return Err(io::Error::other("cannot overwrite existing file"));
+            }
+            File::options().write(true).open(file_name)
+        }
+        res => res,
+    }
+}
+
+/// Stands in for a closed fd
+struct Closed;
+
+impl Read for Closed {
+    fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+        Err(io::Error::from_raw_os_error(libc::EBADF))
+    }
+}
+
+impl Write for Closed {
+    fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+        Err(io::Error::from_raw_os_error(libc::EBADF))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The file descriptors a command runs with. Starts out as the shell's own stdio,
+/// then gets modified by pipes and redirections.
+pub struct FdTable(BTreeMap<i32, OwnedFd>);
+
+impl FdTable {
+    pub fn inherited() -> Self {
+        let dup =
+            |fd: std::os::fd::BorrowedFd| fd.try_clone_to_owned().expect("could not dup stdio");
+        Self(BTreeMap::from([
+            (0, dup(stdin().as_fd())),
+            (1, dup(stdout().as_fd())),
+            (2, dup(stderr().as_fd())),
+        ]))
+    }
+
+    pub fn try_clone(&self) -> io::Result<Self> {
+        self.0
+            .iter()
+            .map(|(fd, owned)| Ok((*fd, owned.try_clone()?)))
+            .collect::<io::Result<_>>()
+            .map(Self)
+    }
+
+    pub fn set(&mut self, fd: i32, to: impl Into<OwnedFd>) {
+        self.0.insert(fd, to.into());
+    }
+
+    pub fn take(&mut self, fd: i32) -> Option<OwnedFd> {
+        self.0.remove(&fd)
+    }
+
+    /// A stream for a builtin to read from, erroring on use if `fd` was closed
+    pub fn input(&self, fd: i32) -> Box<dyn Read + Send> {
+        match self.0.get(&fd).and_then(|fd| fd.try_clone().ok()) {
+            Some(fd) => Box::new(File::from(fd)),
+            None => Box::new(Closed),
+        }
+    }
+
+    /// A stream for a builtin to write to, erroring on use if `fd` was closed
+    pub fn output(&self, fd: i32) -> Box<dyn Write + Send> {
+        match self.0.get(&fd).and_then(|fd| fd.try_clone().ok()) {
+            Some(fd) => Box::new(File::from(fd)),
+            None => Box::new(Closed),
+        }
+    }
+
+    /// removes every fd other than stdin, stdout and stderr
+    pub fn take_others(&mut self) -> Vec<(i32, OwnedFd)> {
+        self.0.split_off(&3).into_iter().collect()
+    }
+
+    /// Applies redirections in order, so later ones see the effects of earlier ones
+    pub fn apply(
+        &mut self,
+        redirects: &[Redirect],
+        shell: &'static Shell,
+    ) -> Result<(), ExpandError> {
+        for r in redirects {
+            match &r.to {
+                RedirectTo::File(file_name) => {
+                    let file_name = expand::expand_to_string(file_name, shell)?;
+                    let options = shell.variables.lock().unwrap().options.clone();
+                    let file = new_file(&r.r_type, file_name, &options)?;
+                    self.set(r.from.raw(), file);
+                }
+                RedirectTo::Fd(to) => {
+                    // both fds end up sharing the same open file
+                    let dup = self
+                        .0
+                        .get(&to.raw())
+                        .ok_or_else(|| io::Error::from_raw_os_error(libc::EBADF))
+                        .and_then(|fd| fd.try_clone())
+                        .map_err(|source| RedirectError {
+                            target: to.raw().to_string(),
+                            source,
+                        })?;
+                    self.set(r.from.raw(), dup);
+                }
+                RedirectTo::Close => {
+                    self.take(r.from.raw());
+                }
+                RedirectTo::HereDoc(word) => {
+                    let text = expand::expand_quoted(word, shell)?;
+                    let (reader, mut writer) = os_pipe::pipe().unwrap();
+                    // written from another thread so a body bigger than the pipe can't block us
+                    thread::spawn(move || writer.write_all(text.as_bytes()));
+                    self.set(r.from.raw(), reader);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_open_noclobber() {
+    let path = std::env::temp_dir().join(format!("shell-noclobber-{}", std::process::id()));
+    let path = path.to_str().unwrap();
+    let _ = fs::remove_file(path);
+
+    // a new file is created, an existing one is left alone
+    open_noclobber(path).unwrap().write_all(b"kept").unwrap();
+    let e = open_noclobber(path).unwrap_err();
+    assert_eq!(describe(&e), "cannot overwrite existing file");
+    assert_eq!(fs::read_to_string(path).unwrap(), "kept");
+    fs::remove_file(path).unwrap();
+
+    // not a regular file, so writing to it can't lose anything
+    assert!(open_noclobber("/dev/null").is_ok());
+}
@@ -84,6 +84,7 @@ impl<'a> InputDisplay<'a> {
 }
 
 pub struct InputState<'a> {
+    prompt: &'a str,
     input_display: InputDisplay<'a>,
     cursor_pos: usize,
     raw: RawTerminal<Stdout>,
@@ -91,8 +92,9 @@ pub struct InputState<'a> {
 }
 
 impl<'a> InputState<'a> {
-    pub fn new() -> io::Result<Self> {
+    pub fn new(prompt: &'a str) -> io::Result<Self> {
         Ok(Self {
+            prompt,
             input_display: InputDisplay {
                 input: String::new(),
                 selected: Selected::Input,
@@ -104,7 +106,7 @@ impl<'a> InputState<'a> {
     }
 
     pub fn begin(&mut self) -> io::Result<()> {
-        self.print("$ ")
+        self.print(self.prompt)
     }
 
     pub fn handle_newline(&mut self) -> io::Result<()> {
@@ -112,6 +114,11 @@ impl<'a> InputState<'a> {
         self.print("\r\n")
     }
 
+    pub fn handle_interrupt(&mut self) -> io::Result<()> {
+        self.rang_bell = false;
+        self.print("^C\r\n")
+    }
+
     pub fn handle_tab(&mut self, command_completer: &Autocompleter) -> io::Result<()> {
         if let Some((_, last_arg)) = self
             .input_display
@@ -0,0 +1,402 @@
+use std::{
+    io::{self, stderr, Write},
+    os::unix::process::ExitStatusExt,
+    process::ExitStatus,
+    thread::JoinHandle,
+};
+
+use crate::shell::Shell;
+
+/// One command of a pipeline that has been started
+pub struct Process {
+    /// None for builtins, which run on a thread of the shell
+    pub pid: Option<u32>,
+    thread: Option<JoinHandle<i32>>,
+    /// the exit status once it has finished
+    status: Option<i32>,
+    stopped: bool,
+}
+
+/// What waitpid reported for a child
+enum Change {
+    Exited(i32),
+    Stopped,
+}
+
+impl Process {
+    pub fn child(pid: u32) -> Self {
+        Self {
+            pid: Some(pid),
+            thread: None,
+            status: None,
+            stopped: false,
+        }
+    }
+
+    pub fn builtin(thread: JoinHandle<i32>) -> Self {
+        Self {
+            pid: None,
+            thread: Some(thread),
+            status: None,
+            stopped: false,
+        }
+    }
+
+    /// A command that never got to run, e.g. because it wasn't found
+    pub fn done(status: i32) -> Self {
+        Self {
+            pid: None,
+            thread: None,
+            status: Some(status),
+            stopped: false,
+        }
+    }
+
+    /// Checks whether the process has finished or stopped, waiting for that if `block` is set
+    fn poll(&mut self, block: bool) {
+        if self.status.is_some() {
+            return;
+        }
+        if let Some(thread) = self.thread.take_if(|t| block || t.is_finished()) {
+            self.status = Some(thread.join().unwrap());
+        } else if let Some(pid) = self.pid {
+            match wait_pid(pid, block) {
+                Some(Change::Exited(status)) => self.status = Some(status),
+                Some(Change::Stopped) => self.stopped = true,
+                None => {}
+            }
+        }
+    }
+}
+
+/// Reaps a child, None if nothing changed and `block` isn't set
+fn wait_pid(pid: u32, block: bool) -> Option<Change> {
+    let flags = libc::WUNTRACED | if block { 0 } else { libc::WNOHANG };
+    let mut raw = 0;
+    loop {
+        match unsafe { libc::waitpid(pid as i32, &mut raw, flags) } {
+            0 => return None,
+            -1 if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => continue,
+            // someone else already reaped it, there is no status left to get
+            -1 => return Some(Change::Exited(1)),
+            _ => break,
+        }
+    }
+    if libc::WIFSTOPPED(raw) {
+        return Some(Change::Stopped);
+    }
+
+    // processes killed by a signal report 128 + the signal number
+    let status = ExitStatus::from_raw(raw);
+    Some(Change::Exited(
+        status
+            .code()
+            .or_else(|| status.signal().map(|sig| 128 + sig))
+            .unwrap_or(1),
+    ))
+}
+
+/// A started pipeline, in the job table if it runs in the background or was stopped
+pub struct Job {
+    /// the job number, 0 until it is added to the job table
+    pub id: usize,
+    /// the pipeline as it was typed
+    pub command: String,
+    processes: Vec<Process>,
+    /// the process group of its children when the shell has job control
+    pgid: Option<u32>,
+}
+
+impl Job {
+    pub fn new(command: String, processes: Vec<Process>, pgid: Option<u32>) -> Self {
+        Self {
+            id: 0,
+            command,
+            processes,
+            pgid,
+        }
+    }
+
+    /// The pid reported for the job, that of its last process
+    pub fn pid(&self) -> Option<u32> {
+        self.processes.iter().rev().find_map(|p| p.pid)
+    }
+
+    fn poll(&mut self) {
+        for process in &mut self.processes {
+            process.poll(false);
+        }
+    }
+
+    /// Waits until every process has finished or the job is stopped
+    fn wait(&mut self) {
+        // children first, a builtin could be stuck writing to a stopped one
+        for process in self.processes.iter_mut().filter(|p| p.pid.is_some()) {
+            if !process.stopped {
+                process.poll(true);
+            }
+            if process.stopped {
+                return;
+            }
+        }
+        for process in &mut self.processes {
+            process.poll(true);
+        }
+    }
+
+    fn is_stopped(&self) -> bool {
+        self.processes
+            .iter()
+            .any(|p| p.stopped && p.status.is_none())
+    }
+
+    /// Sends SIGCONT to every process that hasn't finished
+    fn resume(&mut self) {
+        for process in &mut self.processes {
+            if let (Some(pid), None) = (process.pid, process.status) {
+                unsafe { libc::kill(pid as i32, libc::SIGCONT) };
+                process.stopped = false;
+            }
+        }
+    }
+
+    /// The job's exit status once every process has finished, that of the last one
+    fn status(&self) -> Option<i32> {
+        self.processes
+            .iter()
+            .map(|p| p.status)
+            .reduce(|all, last| all.and(last))
+            .flatten()
+    }
+
+    fn state(&self) -> String {
+        match self.status() {
+            None if self.is_stopped() => "Stopped".into(),
+            None => "Running".into(),
+            Some(0) => "Done".into(),
+            Some(status) => format!("Exit {status}"),
+        }
+    }
+}
+
+/// Every job the shell knows about, the most recent one last
+#[derive(Default)]
+pub struct Jobs(Vec<Job>);
+
+impl Jobs {
+    /// Adds a job as the current one, numbering it one after the highest number in use
+    /// unless it already has a number
+    pub fn add(&mut self, mut job: Job) -> &Job {
+        if job.id == 0 {
+            job.id = self.0.iter().map(|j| j.id).max().unwrap_or(0) + 1;
+        }
+        self.0.push(job);
+        self.0.last().unwrap()
+    }
+
+    /// Finds a job from a job spec: `%n`, `%%`, `%+`, `%-` or `%prefix` of its command.
+    /// Without a spec it is the current job
+    pub fn find(&self, spec: Option<&str>) -> Result<usize, String> {
+        let not_found = || match spec {
+            None => "current: no such job".to_owned(),
+            Some(spec) => format!("{spec}: no such job"),
+        };
+        let last = self.0.len().checked_sub(1);
+
+        let index = match spec.map(|s| s.strip_prefix('%').unwrap_or(s)) {
+            None | Some("%" | "+") => last,
+            Some("-") => last.and_then(|i| i.checked_sub(1)),
+            Some(n) if n.parse::<usize>().is_ok() => {
+                let id = n.parse::<usize>().unwrap();
+                self.0.iter().position(|j| j.id == id)
+            }
+            Some(prefix) => self.0.iter().rposition(|j| j.command.starts_with(prefix)),
+        };
+        index.ok_or_else(not_found)
+    }
+
+    /// The oldest job that is neither stopped nor known to have finished
+    pub fn find_running(&self) -> Option<usize> {
+        self.0
+            .iter()
+            .position(|j| !j.is_stopped() && j.status().is_none())
+    }
+
+    /// The job with the given pid as one of its processes
+    pub fn find_pid(&self, pid: u32) -> Option<usize> {
+        self.0
+            .iter()
+            .position(|j| j.processes.iter().any(|p| p.pid == Some(pid)))
+    }
+
+    pub fn remove(&mut self, index: usize) -> Job {
+        self.0.remove(index)
+    }
+
+    /// `+` for the current job, `-` for the one before it
+    fn marker(&self, index: usize) -> char {
+        match self.0.len() - index {
+            1 => '+',
+            2 => '-',
+            _ => ' ',
+        }
+    }
+
+    fn describe(&self, index: usize, with_pid: bool) -> String {
+        let job = &self.0[index];
+        let pid = match job.pid() {
+            Some(pid) if with_pid => format!(" {pid}"),
+            _ => String::new(),
+        };
+        let background = if job.state() == "Running" { " &" } else { "" };
+        format!(
+            "[{}]{}{pid}  {:<24}{}{background}",
+            job.id,
+            self.marker(index),
+            job.state(),
+            job.command
+        )
+    }
+
+    /// Writes a line for each job that has finished since the last call, and forgets them
+    pub fn report_finished(&mut self, out: &mut impl Write) -> io::Result<()> {
+        for job in &mut self.0 {
+            job.poll();
+        }
+        for index in 0..self.0.len() {
+            if self.0[index].status().is_some() {
+                writeln!(out, "{}", self.describe(index, false))?;
+            }
+        }
+        self.0.retain(|job| job.status().is_none());
+        Ok(())
+    }
+
+    /// Writes a line for every job, like `report_finished` it forgets the finished ones
+    pub fn list(&mut self, out: &mut impl Write, with_pid: bool) -> io::Result<()> {
+        for job in &mut self.0 {
+            job.poll();
+        }
+        for index in 0..self.0.len() {
+            writeln!(out, "{}", self.describe(index, with_pid))?;
+        }
+        self.0.retain(|job| job.status().is_none());
+        Ok(())
+    }
+
+    /// Writes the pid of every job
+    pub fn list_pids(&self, out: &mut impl Write) -> io::Result<()> {
+        for pid in self.0.iter().filter_map(Job::pid) {
+            writeln!(out, "{pid}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Gives the terminal to a process group
+fn give_terminal(pgid: u32) {
+    unsafe { libc::tcsetpgrp(libc::STDIN_FILENO, pgid as i32) };
+}
+
+/// Takes the terminal back for the shell, with the settings it had when the shell started
+fn take_terminal(shell: &Shell) {
+    unsafe {
+        libc::tcsetpgrp(libc::STDIN_FILENO, libc::getpgrp());
+        if let Some(termios) = &shell.termios {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSADRAIN, termios);
+        }
+    }
+}
+
+/// Waits for a job with the terminal given to it. If it gets stopped it is put in the job
+/// table, otherwise its exit status is returned
+pub fn foreground(shell: &Shell, mut job: Job) -> i32 {
+    let pgid = job.pgid.filter(|_| shell.job_control);
+    if let Some(pgid) = pgid {
+        give_terminal(pgid);
+    }
+    job.resume();
+    job.wait();
+    if pgid.is_some() {
+        take_terminal(shell);
+    }
+
+    match job.status() {
+        Some(status) => {
+            // the terminal echoed ^C, the next prompt shouldn't follow it on the same line
+            if pgid.is_some() && status == 128 + libc::SIGINT {
+                let _ = writeln!(stderr());
+            }
+            status
+        }
+        None => {
+            let mut jobs = shell.jobs.lock().unwrap();
+            jobs.add(job);
+            let _ = writeln!(stderr(), "\n{}", jobs.describe(jobs.0.len() - 1, false));
+            128 + libc::SIGTSTP
+        }
+    }
+}
+
+/// Continues a stopped job without waiting for it, erroring if it is already running
+pub fn background(shell: &Shell, index: usize) -> Result<String, String> {
+    let mut jobs = shell.jobs.lock().unwrap();
+    if !jobs.0[index].is_stopped() {
+        return Err(format!("job {} already in background", jobs.0[index].id));
+    }
+    let mut job = jobs.remove(index);
+    job.resume();
+    let line = format!("[{}]+ {} &", job.id, job.command);
+    jobs.add(job);
+    Ok(line)
+}
+
+/// Waits for a job to finish or stop, removing it from the job table if it finished
+pub fn wait(shell: &Shell, index: usize) -> i32 {
+    let mut job = shell.jobs.lock().unwrap().remove(index);
+    job.wait();
+    match job.status() {
+        Some(status) => status,
+        None => {
+            shell.jobs.lock().unwrap().add(job);
+            128 + libc::SIGTSTP
+        }
+    }
+}
+
+/// Puts the shell in its own process group in charge of the terminal
+pub fn init_job_control() {
+    unsafe {
+        // fails if the shell already leads its session, which is fine
+        libc::setpgid(0, 0);
+        libc::tcsetpgrp(libc::STDIN_FILENO, libc::getpgrp());
+    }
+}
+
+#[test]
+fn test_find() {
+    let mut jobs = Jobs::default();
+    for command in ["sleep 10", "make all", "sleep 20"] {
+        jobs.add(Job::new(command.into(), vec![Process::done(0)], None));
+    }
+
+    // the current job is the newest, the previous one comes before it
+    assert_eq!(jobs.find(None), Ok(2));
+    assert_eq!(jobs.find(Some("%%")), Ok(2));
+    assert_eq!(jobs.find(Some("%+")), Ok(2));
+    assert_eq!(jobs.find(Some("%-")), Ok(1));
+    assert_eq!(jobs.find(Some("%2")), Ok(1));
+    assert_eq!(jobs.find(Some("3")), Ok(2));
+    // the newest job whose command starts with it
+    assert_eq!(jobs.find(Some("%sleep")), Ok(2));
+    assert_eq!(jobs.find(Some("%make")), Ok(1));
+    assert_eq!(jobs.find(Some("%4")), Err("%4: no such job".to_owned()));
+    assert_eq!(
+        jobs.find(Some("%cargo")),
+        Err("%cargo: no such job".to_owned())
+    );
+
+    let jobs = Jobs::default();
+    assert_eq!(jobs.find(None), Err("current: no such job".to_owned()));
+    assert!(jobs.find(Some("%-")).is_err());
+}
@@ -1,7 +1,15 @@
-use autocompleter::build_command_completer;
+use autocompleter::{build_command_completer, Autocompleter};
 use history::History;
 use input_state::InputState;
-use std::{env, io, sync::Mutex};
+use shell::Shell;
+use std::{
+    env,
+    fs::File,
+    io::{self, BufReader, IsTerminal},
+    os::fd::AsFd,
+    path::PathBuf,
+    process::ExitCode,
+};
 use termion::{event::Key, input::TermRead};
 
 use crate::command::RunResult;
@@ -10,52 +18,349 @@ mod command;
 mod autocompleter;
 mod history;
 mod input_state;
+mod jobs;
+mod shell;
+mod signals;
+mod variables;
 
-fn main() -> io::Result<()> {
-    let command_completer = build_command_completer();
+/// Where the shell reads its commands from
+enum Input {
+    /// the line editor, stdin is a terminal
+    Interactive,
+    /// `-c command`
+    String(String),
+    Script(PathBuf),
+    /// stdin that isn't a terminal, e.g. a pipe
+    Stdin,
+}
+
+/// What the command line asks for
+struct Args {
+    input: Input,
+    /// `$0`
+    arg0: String,
+    /// `$1`, `$2`, ...
+    positional: Vec<String>,
+    /// `-l`, `--login`, or started with a `-` in front of its name
+    login: bool,
+    /// `--norc`
+    norc: bool,
+    /// `--rcfile file`
+    rcfile: Option<PathBuf>,
+}
+
+/// Parses `shell [options] -c command [arg0 [args...]]`, `shell [options] script [args...]`
+/// or `shell [options]`
+fn parse_args(args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut args = args.peekable();
+    let shell_name = args.next().unwrap_or_else(|| "shell".into());
+
+    let mut login = shell_name.starts_with('-');
+    let mut norc = false;
+    let mut rcfile = None;
+    loop {
+        match args.peek().map(String::as_str) {
+            Some("-l" | "--login") => login = true,
+            Some("--norc") => norc = true,
+            Some("--rcfile") => {
+                args.next();
+                let file = args.peek().ok_or("--rcfile: option requires an argument")?;
+                rcfile = Some(file.into());
+            }
+            _ => break,
+        }
+        args.next();
+    }
+
+    let (input, arg0) = match args.next() {
+        None if io::stdin().is_terminal() => (Input::Interactive, shell_name),
+        None => (Input::Stdin, shell_name),
+        Some(flag) if flag == "-c" => {
+            let command = args.next().ok_or("-c: option requires an argument")?;
+            (Input::String(command), args.next().unwrap_or(shell_name))
+        }
+        Some(flag) if flag.starts_with('-') && flag != "-" => {
+            return Err(format!("{flag}: invalid option"))
+        }
+        Some(script) => (Input::Script(script.clone().into()), script),
+    };
+
+    Ok(Args {
+        input,
+        arg0,
+        positional: args.collect(),
+        login,
+        norc,
+        rcfile,
+    })
+}
+
+/// The file sourced before any commands run, `~/.profile` for login shells and otherwise the
+/// rc file for interactive ones. Also whether it was named explicitly rather than by default
+fn startup_file(args: &Args) -> Option<(PathBuf, bool)> {
+    let home = env::var_os("HOME").map(PathBuf::from);
+    if args.login {
+        return home.map(|home| (home.join(".profile"), false));
+    }
+    if !matches!(args.input, Input::Interactive) || args.norc {
+        return None;
+    }
+    let rcfile = (args.rcfile.clone()).or_else(|| env::var_os("SHELLRC").map(PathBuf::from));
+    match rcfile {
+        Some(rcfile) => Some((rcfile, true)),
+        None => home.map(|home| (home.join(".shellrc"), false)),
+    }
+}
+
+fn main() -> io::Result<ExitCode> {
+    let args = match parse_args(env::args()) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("shell: {e}");
+            return Ok(ExitCode::from(2));
+        }
+    };
+    let interactive = matches!(args.input, Input::Interactive);
 
-    let hist_file_env = env::var("HISTFILE");
-    let mut history = Mutex::new(
+    // only interactive shells keep a history
+    let hist_file_env = env::var("HISTFILE").ok().filter(|_| interactive);
+    // lives as long as the program, background jobs hold on to it from their own threads
+    let shell: &'static Shell = Box::leak(Box::new(Shell::new(
         hist_file_env
             .as_ref()
-            .ok()
             .and_then(|path| History::from_file(path.into()))
             .unwrap_or_default(),
-    );
+        interactive,
+    )));
+    let startup_file = startup_file(&args);
+    {
+        let mut vars = shell.variables.lock().unwrap();
+        vars.arg0 = args.arg0;
+        vars.positional = args.positional;
+    }
 
-    'a: loop {
-        let mut input = InputState::new()?;
-        input.begin()?;
+    let startup = match startup_file {
+        Some((path, explicit)) => match File::open(&path) {
+            Ok(file) => command::run_lines(&path.to_string_lossy(), BufReader::new(file), shell),
+            Err(e) => {
+                // the default files are optional
+                if explicit {
+                    eprintln!("shell: {}: {}", path.display(), command::describe_error(&e));
+                }
+                RunResult::Continue(0)
+            }
+        },
+        None => RunResult::Continue(0),
+    };
+
+    let exit_status = match args.input {
+        // `exit` in a startup file leaves before anything else runs
+        _ if matches!(startup, RunResult::Exit(_)) => startup.status(),
+        Input::Interactive => repl(shell)?,
+        Input::String(command) => {
+            command::run_lines("shell: -c", command.as_bytes(), shell).status()
+        }
+        Input::Script(path) => match File::open(&path) {
+            Ok(file) => {
+                command::run_lines(&path.to_string_lossy(), BufReader::new(file), shell).status()
+            }
+            Err(e) => {
+                eprintln!("shell: {}: {}", path.display(), command::describe_error(&e));
+                return Ok(ExitCode::from(127));
+            }
+        },
+        Input::Stdin => {
+            // read a byte at a time, so the commands that run get the input after their line
+            let stdin = File::from(io::stdin().as_fd().try_clone_to_owned()?);
+            command::run_lines("shell", BufReader::with_capacity(1, stdin), shell).status()
+        }
+    };
 
-        let history_handle = history.get_mut().unwrap();
+    let exit_status = command::run_exit_trap(shell, exit_status);
+
+    if let Some(path) = hist_file_env {
+        let _ = shell
+            .history
+            .lock()
+            .unwrap()
+            .write_to_file(path.into(), false);
+    };
 
-        for key in io::stdin().keys().filter_map(Result::ok) {
-            match key {
-                Key::Char('\n') => {
-                    input.handle_newline()?;
-                    break;
+    Ok(ExitCode::from(exit_status as u8))
+}
+
+/// Reads and runs commands from the line editor until `exit` or ctrl-d, returning the status
+/// to exit with
+fn repl(shell: &'static Shell) -> io::Result<i32> {
+    let command_completer = build_command_completer();
+    // exiting through ctrl-d leaves with the status of the last command
+    let mut exit_status = 0;
+
+    'prompt: loop {
+        if let RunResult::Exit(status) = command::run_pending_traps(shell) {
+            exit_status = status;
+            break;
+        }
+        shell
+            .jobs
+            .lock()
+            .unwrap()
+            .report_finished(&mut io::stderr())?;
+
+        let mut history_handle = shell.history.lock().unwrap();
+        let mut input = match read_line("$ ", &command_completer, &history_handle)? {
+            ReadLine::Line(input) => input,
+            ReadLine::Interrupted => {
+                shell.variables.lock().unwrap().last_status = INTERRUPTED;
+                continue;
+            }
+            ReadLine::Eof => break,
+        };
+
+        // keep reading lines until the command is complete, e.g. every here-document is closed
+        while command::is_incomplete(&input) {
+            let line = match read_line("> ", &command_completer, &history_handle)? {
+                ReadLine::Line(line) => line,
+                // the whole command is thrown away, not just this line
+                ReadLine::Interrupted => {
+                    shell.variables.lock().unwrap().last_status = INTERRUPTED;
+                    continue 'prompt;
                 }
-                Key::Char('\t') => input.handle_tab(&command_completer),
-                Key::Char(c) => input.handle_char(c),
-                Key::Backspace => input.handle_backspace(),
-                Key::Left => input.handle_left(),
-                Key::Right => input.handle_right(),
-                Key::Up => input.handle_up(history_handle),
-                Key::Down => input.handle_down(history_handle),
-                Key::Ctrl('d') => break 'a,
-                _ => Ok(()),
-            }?;
+                ReadLine::Eof => break,
+            };
+            input.push('\n');
+            input.push_str(&line);
         }
 
-        history_handle.push(input.submit());
-        if command::run_from_history(&history) == RunResult::Exit {
-            break 'a;
+        history_handle.push(input);
+        drop(history_handle);
+        match command::run_from_history(shell) {
+            RunResult::Exit(status) => {
+                exit_status = status;
+                break;
+            }
+            RunResult::Continue(status) => exit_status = status,
         }
     }
 
-    if let Ok(path) = hist_file_env {
-        let _ = history.get_mut().unwrap().write_to_file(path.into(), false);
+    Ok(exit_status)
+}
+
+/// The status left in `$?` when ctrl-c discards a line, as if SIGINT ended a command
+const INTERRUPTED: i32 = 128 + libc::SIGINT;
+
+enum ReadLine {
+    Line(String),
+    /// ctrl-c, the line is discarded
+    Interrupted,
+    /// ctrl-d
+    Eof,
+}
+
+/// Reads a line with the line editor
+fn read_line(
+    prompt: &str,
+    command_completer: &Autocompleter,
+    history: &History,
+) -> io::Result<ReadLine> {
+    let mut input = InputState::new(prompt)?;
+    input.begin()?;
+
+    for key in io::stdin().keys().filter_map(Result::ok) {
+        match key {
+            Key::Char('\n') => {
+                input.handle_newline()?;
+                break;
+            }
+            Key::Char('\t') => input.handle_tab(command_completer),
+            Key::Char(c) => input.handle_char(c),
+            Key::Backspace => input.handle_backspace(),
+            Key::Left => input.handle_left(),
+            Key::Right => input.handle_right(),
+            Key::Up => input.handle_up(history),
+            Key::Down => input.handle_down(history),
+            Key::Ctrl('c') => {
+                input.handle_interrupt()?;
+                return Ok(ReadLine::Interrupted);
+            }
+            Key::Ctrl('d') => return Ok(ReadLine::Eof),
+            _ => Ok(()),
+        }?;
+    }
+
+    Ok(ReadLine::Line(input.submit()))
+}
+
+#[test]
+fn test_parse_args() {
+    let parse = |args: &[&str]| parse_args(args.iter().map(|s| s.to_string()));
+
+    let args = parse(&["shell", "-c", "echo $0 $1", "name", "a", "b"]).unwrap();
+    assert!(matches!(&args.input, Input::String(command) if command == "echo $0 $1"));
+    assert_eq!(args.arg0, "name");
+    assert_eq!(args.positional, ["a", "b"]);
+    assert!(!args.login && !args.norc);
+
+    // without arg0 it is the shell's own name
+    let args = parse(&["shell", "-c", "true"]).unwrap();
+    assert_eq!(args.arg0, "shell");
+    assert!(args.positional.is_empty());
+
+    let args = parse(&["shell", "-l", "--norc", "--rcfile", "rc", "script", "x"]).unwrap();
+    assert!(matches!(&args.input, Input::Script(path) if path.as_os_str() == "script"));
+    assert_eq!(args.arg0, "script");
+    assert_eq!(args.positional, ["x"]);
+    assert!(args.login && args.norc);
+    assert_eq!(args.rcfile, Some(PathBuf::from("rc")));
+
+    assert!(parse(&["-shell", "-c", "true"]).unwrap().login);
+    assert!(parse(&["shell", "--login", "-c", "true"]).unwrap().login);
+
+    assert_eq!(
+        parse(&["shell", "-c"]).err().unwrap(),
+        "-c: option requires an argument"
+    );
+    assert_eq!(
+        parse(&["shell", "--rcfile"]).err().unwrap(),
+        "--rcfile: option requires an argument"
+    );
+    assert_eq!(parse(&["shell", "-x"]).err().unwrap(), "-x: invalid option");
+}
+
+#[test]
+fn test_startup_file() {
+    let args = |input, login, norc, rcfile: Option<&str>| Args {
+        input,
+        arg0: "shell".into(),
+        positional: vec![],
+        login,
+        norc,
+        rcfile: rcfile.map(PathBuf::from),
     };
+    let home = env::var_os("HOME").map(PathBuf::from);
 
-    Ok(())
+    // login shells read ~/.profile whatever else they are given
+    assert_eq!(
+        startup_file(&args(Input::Stdin, true, true, Some("rc"))),
+        home.as_ref().map(|home| (home.join(".profile"), false))
+    );
+    // only interactive shells have an rc file
+    assert_eq!(
+        startup_file(&args(Input::Script("s".into()), false, false, Some("rc"))),
+        None
+    );
+    assert_eq!(
+        startup_file(&args(Input::Interactive, false, true, Some("rc"))),
+        None
+    );
+    assert_eq!(
+        startup_file(&args(Input::Interactive, false, false, Some("rc"))),
+        Some((PathBuf::from("rc"), true))
+    );
+    if env::var_os("SHELLRC").is_none() {
+        assert_eq!(
+            startup_file(&args(Input::Interactive, false, false, None)),
+            home.map(|home| (home.join(".shellrc"), false))
+        );
+    }
 }
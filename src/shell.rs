@@ -0,0 +1,65 @@
+use std::{
+    io::{self, IsTerminal},
+    mem::MaybeUninit,
+    sync::{atomic::AtomicBool, Mutex},
+};
+
+use crate::{
+    command::FdTable,
+    history::History,
+    jobs::{self, Jobs},
+    signals::{self, Traps},
+    variables::Variables,
+};
+
+/// State that lives as long as the shell does, shared by every command it runs
+pub struct Shell {
+    pub history: Mutex<History>,
+    pub variables: Mutex<Variables>,
+    /// fds every command starts out with, changed by `exec` redirections
+    pub fds: Mutex<FdTable>,
+    pub jobs: Mutex<Jobs>,
+    pub traps: Mutex<Traps>,
+    /// set by builtins that ran an `exit` of their own, e.g. `source`
+    pub exit_requested: AtomicBool,
+    /// pipelines get their own process group and are given the terminal in the foreground
+    pub job_control: bool,
+    /// the terminal settings the shell started with, restored when a job gives it back
+    pub termios: Option<libc::termios>,
+}
+
+impl Shell {
+    /// `interactive` is for a shell reading commands typed at a terminal, rather than
+    /// running a script
+    pub fn new(history: History, interactive: bool) -> Self {
+        let job_control = interactive && io::stdin().is_terminal();
+        if job_control {
+            signals::ignore();
+            jobs::init_job_control();
+        }
+
+        let mut termios = MaybeUninit::uninit();
+        let termios = (unsafe { libc::tcgetattr(libc::STDIN_FILENO, termios.as_mut_ptr()) } == 0)
+            .then(|| unsafe { termios.assume_init() });
+
+        Self {
+            history: Mutex::new(history),
+            variables: Mutex::new(Variables::from_env()),
+            fds: Mutex::new(FdTable::inherited()),
+            jobs: Mutex::default(),
+            traps: Mutex::default(),
+            exit_requested: AtomicBool::new(false),
+            job_control,
+            termios,
+        }
+    }
+
+    /// Where commands are looked up, the shell's PATH variable
+    pub fn path(&self) -> String {
+        self.variables
+            .lock()
+            .unwrap()
+            .get("PATH")
+            .unwrap_or_default()
+    }
+}
@@ -0,0 +1,186 @@
+use std::{
+    collections::BTreeMap,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use libc::c_int;
+
+/// Signals an interactive shell ignores. Keyboard signals then only reach the foreground
+/// job, and the shell can take the terminal back while it isn't in the foreground
+const IGNORED: [c_int; 5] = [
+    libc::SIGINT,
+    libc::SIGQUIT,
+    libc::SIGTSTP,
+    libc::SIGTTIN,
+    libc::SIGTTOU,
+];
+
+pub fn ignore() {
+    for sig in IGNORED {
+        unsafe { libc::signal(sig, libc::SIG_IGN) };
+    }
+}
+
+/// The signals a child needs the default handling of put back: the ones the shell ignores
+/// for job control, except those a `trap ''` ignores on purpose, which children inherit
+pub fn to_reset(job_control: bool, traps: &Traps) -> Vec<c_int> {
+    if !job_control {
+        return vec![];
+    }
+    IGNORED
+        .into_iter()
+        .filter(|sig| traps.get(*sig) != Some(""))
+        .collect()
+}
+
+/// Puts back the default handling of `signals`, from `to_reset`. Called in children
+/// before exec, so it must not allocate
+pub fn reset_in_child(signals: &[c_int]) {
+    for sig in signals {
+        unsafe { libc::signal(*sig, libc::SIG_DFL) };
+    }
+}
+
+/// Signals `trap` knows by name, in the order `trap -l` lists them
+const NAMES: [(&str, c_int); 29] = [
+    ("HUP", libc::SIGHUP),
+    ("INT", libc::SIGINT),
+    ("QUIT", libc::SIGQUIT),
+    ("ILL", libc::SIGILL),
+    ("TRAP", libc::SIGTRAP),
+    ("ABRT", libc::SIGABRT),
+    ("BUS", libc::SIGBUS),
+    ("FPE", libc::SIGFPE),
+    ("KILL", libc::SIGKILL),
+    ("USR1", libc::SIGUSR1),
+    ("SEGV", libc::SIGSEGV),
+    ("USR2", libc::SIGUSR2),
+    ("PIPE", libc::SIGPIPE),
+    ("ALRM", libc::SIGALRM),
+    ("TERM", libc::SIGTERM),
+    ("CHLD", libc::SIGCHLD),
+    ("CONT", libc::SIGCONT),
+    ("STOP", libc::SIGSTOP),
+    ("TSTP", libc::SIGTSTP),
+    ("TTIN", libc::SIGTTIN),
+    ("TTOU", libc::SIGTTOU),
+    ("URG", libc::SIGURG),
+    ("XCPU", libc::SIGXCPU),
+    ("XFSZ", libc::SIGXFSZ),
+    ("VTALRM", libc::SIGVTALRM),
+    ("PROF", libc::SIGPROF),
+    ("WINCH", libc::SIGWINCH),
+    ("IO", libc::SIGIO),
+    ("SYS", libc::SIGSYS),
+];
+
+/// The pseudo-signal for the trap run when the shell exits
+pub const EXIT: c_int = 0;
+
+/// Parses a signal spec like `INT`, `SIGINT`, `sigint`, `2` or `EXIT`
+pub fn parse(spec: &str) -> Option<c_int> {
+    if let Ok(sig) = spec.parse() {
+        return (sig == EXIT || NAMES.iter().any(|(_, s)| *s == sig)).then_some(sig);
+    }
+    let upper = spec.to_ascii_uppercase();
+    let name = upper.strip_prefix("SIG").unwrap_or(&upper);
+    if name == "EXIT" {
+        return Some(EXIT);
+    }
+    NAMES.iter().find(|(n, _)| *n == name).map(|(_, sig)| *sig)
+}
+
+/// The name `trap -p` shows for a signal, e.g. SIGINT
+pub fn name(sig: c_int) -> String {
+    match NAMES.iter().find(|(_, s)| *s == sig) {
+        Some((name, _)) => format!("SIG{name}"),
+        None => "EXIT".to_owned(),
+    }
+}
+
+/// Every signal as `trap -l` lists them, e.g. ` 2) SIGINT`
+pub fn list() -> impl Iterator<Item = String> {
+    let mut sorted = NAMES;
+    sorted.sort_by_key(|(_, sig)| *sig);
+    sorted
+        .into_iter()
+        .map(|(name, sig)| format!("{sig:2}) SIG{name}"))
+}
+
+/// Set by the handler of trapped signals, until the main loop runs their traps
+static PENDING: [AtomicBool; 65] = [const { AtomicBool::new(false) }; 65];
+
+extern "C" fn on_signal(sig: c_int) {
+    PENDING[sig as usize].store(true, Ordering::Relaxed);
+}
+
+/// The trapped signals that arrived since the last call
+pub fn take_pending() -> Vec<c_int> {
+    NAMES
+        .iter()
+        .map(|(_, sig)| *sig)
+        .filter(|sig| PENDING[*sig as usize].swap(false, Ordering::Relaxed))
+        .collect()
+}
+
+/// Commands set with `trap`, by signal number
+#[derive(Default)]
+pub struct Traps(BTreeMap<c_int, String>);
+
+impl Traps {
+    pub fn get(&self, sig: c_int) -> Option<&str> {
+        self.0.get(&sig).map(String::as_str)
+    }
+
+    /// Runs `command` when `sig` arrives, an empty command ignores the signal
+    pub fn set(&mut self, sig: c_int, command: String) {
+        if sig != EXIT {
+            let handler = if command.is_empty() {
+                libc::SIG_IGN
+            } else {
+                on_signal as extern "C" fn(c_int) as libc::sighandler_t
+            };
+            unsafe { libc::signal(sig, handler) };
+        }
+        self.0.insert(sig, command);
+    }
+
+    /// Goes back to how the shell handled `sig` before it was trapped
+    pub fn reset(&mut self, sig: c_int, interactive: bool) {
+        if sig != EXIT {
+            let handler = if interactive && IGNORED.contains(&sig) {
+                libc::SIG_IGN
+            } else {
+                libc::SIG_DFL
+            };
+            unsafe { libc::signal(sig, handler) };
+        }
+        self.0.remove(&sig);
+    }
+
+    /// Every trap as the command that would set it again
+    pub fn list(&self) -> impl Iterator<Item = String> + '_ {
+        self.0.iter().map(|(sig, command)| {
+            format!(
+                "trap -- '{}' {}",
+                command.replace('\'', "'\\''"),
+                name(*sig)
+            )
+        })
+    }
+}
+
+#[test]
+fn test_parse() {
+    for spec in ["INT", "SIGINT", "sigint", "Int", "2"] {
+        assert_eq!(parse(spec), Some(libc::SIGINT), "{spec}");
+    }
+    assert_eq!(parse("EXIT"), Some(EXIT));
+    assert_eq!(parse("sigexit"), Some(EXIT));
+    assert_eq!(parse("0"), Some(EXIT));
+    assert_eq!(parse("TERM"), Some(libc::SIGTERM));
+
+    for spec in ["", "SIG", "NOPE", "SIGNOPE", "-1", "999", "INT2"] {
+        assert_eq!(parse(spec), None, "{spec}");
+    }
+}
@@ -0,0 +1,171 @@
+use std::{
+    collections::{HashMap, HashSet},
+    env, process,
+};
+
+/// Shell variables, seeded from the environment the shell was started with
+#[derive(Clone)]
+pub struct Variables {
+    vars: HashMap<String, String>,
+    /// names passed on to child processes, a name can be exported before it is set
+    exported: HashSet<String>,
+    pub last_status: i32,
+    pub last_background_pid: Option<u32>,
+    pub options: Options,
+    /// `$0`, the shell or the script it runs
+    pub arg0: String,
+    /// `$1`, `$2`, ...
+    pub positional: Vec<String>,
+}
+
+/// Shell options, toggled with `set`
+#[derive(Default, Clone)]
+pub struct Options {
+    /// `>` refuses to overwrite an existing regular file, `>|` still can
+    pub noclobber: bool,
+    /// a glob pattern that matches nothing expands to no words, rather than itself
+    pub nullglob: bool,
+    /// a glob pattern that matches nothing is an error, the command doesn't run
+    pub failglob: bool,
+    /// glob patterns match names starting with `.` without the `.` being spelled out
+    pub dotglob: bool,
+    /// `**` on its own in a glob pattern matches any number of directories
+    pub globstar: bool,
+}
+
+impl Options {
+    /// The options `set -o` knows about, by name
+    pub fn by_name(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "noclobber" => Some(&mut self.noclobber),
+            "nullglob" => Some(&mut self.nullglob),
+            "failglob" => Some(&mut self.failglob),
+            "dotglob" => Some(&mut self.dotglob),
+            "globstar" => Some(&mut self.globstar),
+            _ => None,
+        }
+    }
+
+    pub fn names() -> &'static [&'static str] {
+        &["dotglob", "failglob", "globstar", "noclobber", "nullglob"]
+    }
+}
+
+impl Variables {
+    pub fn from_env() -> Self {
+        let vars: HashMap<_, _> = env::vars().collect();
+        Self {
+            exported: vars.keys().cloned().collect(),
+            vars,
+            last_status: 0,
+            last_background_pid: None,
+            options: Options::default(),
+            arg0: String::new(),
+            positional: vec![],
+        }
+    }
+
+    /// Looks up a variable, a positional parameter or one of the special parameters
+    /// `?`, `$`, `!`, `#`, `@` and `*`
+    pub fn get(&self, name: &str) -> Option<String> {
+        match name {
+            "?" => Some(self.last_status.to_string()),
+            "$" => Some(process::id().to_string()),
+            "!" => self.last_background_pid.map(|pid| pid.to_string()),
+            "#" => Some(self.positional.len().to_string()),
+            "@" => Some(self.positional.join(" ")),
+            // joined with the first character of IFS
+            "*" => {
+                let ifs = self.vars.get("IFS").map_or(" ", String::as_str);
+                let separator = ifs.chars().next().map(String::from).unwrap_or_default();
+                Some(self.positional.join(&separator))
+            }
+            "0" => Some(self.arg0.clone()),
+            _ if name.bytes().all(|b| b.is_ascii_digit()) => {
+                let n: usize = name.parse().ok()?;
+                // `${00}` is 0 too, which has no positional parameter before it
+                n.checked_sub(1)
+                    .and_then(|i| self.positional.get(i))
+                    .cloned()
+            }
+            _ => self.vars.get(name).cloned(),
+        }
+    }
+
+    pub fn set(&mut self, name: &str, value: String) {
+        self.vars.insert(name.to_owned(), value);
+    }
+
+    pub fn unset(&mut self, name: &str) {
+        self.vars.remove(name);
+        self.exported.remove(name);
+    }
+
+    pub fn export(&mut self, name: &str) {
+        self.exported.insert(name.to_owned());
+    }
+
+    /// Keeps the variable, but only in the shell
+    pub fn unexport(&mut self, name: &str) {
+        self.exported.remove(name);
+    }
+
+    /// The environment child processes get
+    pub fn exported(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.exported
+            .iter()
+            .filter_map(|name| Some((name.as_str(), self.vars.get(name)?.as_str())))
+    }
+
+    /// Every exported name with its value if it has one, sorted by name
+    pub fn exported_sorted(&self) -> Vec<(&str, Option<&str>)> {
+        let mut exported: Vec<_> = self
+            .exported
+            .iter()
+            .map(|name| (name.as_str(), self.vars.get(name).map(String::as_str)))
+            .collect();
+        exported.sort();
+        exported
+    }
+}
+
+/// true if `name` can be assigned to, i.e. a letter or underscore followed by letters,
+/// digits and underscores
+pub fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[test]
+fn test_export() {
+    let mut vars = Variables::from_env();
+    let exported = |vars: &Variables, name| vars.exported().any(|(n, _)| n == name);
+
+    vars.set("t_shell_only", "1".into());
+    assert!(!exported(&vars, "t_shell_only"));
+
+    // exported before it has a value, only passed on once it has one
+    vars.export("t_later");
+    assert!(!exported(&vars, "t_later"));
+    assert_eq!(
+        vars.exported_sorted()
+            .into_iter()
+            .find(|(name, _)| *name == "t_later"),
+        Some(("t_later", None))
+    );
+    vars.set("t_later", "2".into());
+    assert!(vars.exported().any(|var| var == ("t_later", "2")));
+
+    // `export -n` keeps the variable in the shell
+    vars.unexport("t_later");
+    assert!(!exported(&vars, "t_later"));
+    assert_eq!(vars.get("t_later").as_deref(), Some("2"));
+
+    vars.export("t_later");
+    vars.unset("t_later");
+    assert!(!exported(&vars, "t_later"));
+    assert_eq!(vars.get("t_later"), None);
+}